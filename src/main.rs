@@ -1,21 +1,137 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use dialoguer::{theme::ColorfulTheme, Select};
+use base64::Engine;
+use blake2::{Blake2s256, Digest};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use clap::{CommandFactory, Parser};
+use dialoguer::console::style;
+use dialoguer::theme::{ColorfulTheme, SimpleTheme, Theme};
+use dialoguer::{Confirm, FuzzySelect, Input, Password, Select};
+use fs2::FileExt;
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     fs,
-    path::PathBuf,
+    io::{IsTerminal, Read, Write},
+    path::{Path, PathBuf},
     process::Command as StdCommand,
+    sync::{Mutex, OnceLock},
 };
 use toml_edit::{DocumentMut, Item};
+use url::Url;
 use reqwest::blocking::Client as BlockingHttpClient;
 use reqwest::header::CONTENT_LENGTH;
 
 const APP_DIR_NAME: &str = "spacetime-token"; // Renamed
 const DEFAULT_PROFILES_FILENAME: &str = "profiles.toml"; // Renamed
 const DEFAULT_CONFIG_FILENAME: &str = "config.toml";
+const LOCK_FILENAME: &str = ".lock";
+const LOCAL_PROFILES_DIR: &str = ".spacetime-token";
+const AUDIT_LOG_FILENAME: &str = "audit.log";
+const CLI_TOML_FINGERPRINT_FILENAME: &str = "cli_toml.fingerprint";
+const PROFILE_HISTORY_FILENAME: &str = "history.toml";
 const SPACETIME_CLI_COMMAND: &str = "spacetime";
+/// Subcommands whose first positional argument is a stored profile name.
+const PROFILE_NAME_SUBCOMMANDS: &[&str] = &[
+    "switch",
+    "delete",
+    "save",
+    "set-address",
+    "set-default",
+    "ping",
+    "show",
+    "diff",
+    "tag",
+    "untag",
+];
+/// server_configs nicknames SpacetimeDB ships with; never pruned by `sync --prune`.
+const BUILTIN_SERVER_NICKNAMES: &[&str] = &["local", "maincloud"];
+const KEYRING_SERVICE: &str = "spacetime-token";
+const ENCRYPTED_TOKEN_PREFIX: &str = "enc:v1:";
+/// Tokens of the form `${ENV:NAME}` are read from the environment variable NAME.
+const ENV_TOKEN_PREFIX: &str = "${ENV:";
+const PASSPHRASE_ENV_VAR: &str = "SPACETIME_TOKEN_PASSPHRASE";
+/// Stands in for a server-issued token under `--dry-run`, which makes no identity request.
+const DRY_RUN_TOKEN: &str = "dry-run-placeholder-token";
+const CONFIG_DIR_ENV_VARS: &[&str] = &["SPACETIME_TOKEN_CONFIG", "SPACETIME_TOKEN_HOME"];
+const TOKEN_SALT_LEN: usize = 16;
+const TOKEN_NONCE_LEN: usize = 12;
+
+// Per-invocation caches so the passphrase is asked for (and Argon2 run) at most once.
+static TOKEN_PASSPHRASE: OnceLock<String> = OnceLock::new();
+static TOKEN_KEYS: Mutex<BTreeMap<[u8; TOKEN_SALT_LEN], [u8; 32]>> = Mutex::new(BTreeMap::new());
+static TOKEN_WRITE_SALT: OnceLock<[u8; TOKEN_SALT_LEN]> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+static DRY_RUN: OnceLock<bool> = OnceLock::new();
+static JSON_OUTPUT: OnceLock<bool> = OnceLock::new();
+static MASK_REVEAL: OnceLock<usize> = OnceLock::new();
+static CONFIG_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+static INSECURE_TLS: OnceLock<bool> = OnceLock::new();
+static HTTP_CLIENT: OnceLock<BlockingHttpClient> = OnceLock::new();
+
+/// Whether `--dry-run` is set; file writes and external commands are then only previewed.
+fn dry_run() -> bool {
+    DRY_RUN.get().copied().unwrap_or(false)
+}
+
+/// Whether `--insecure` is set; HTTP clients then skip TLS certificate verification.
+fn insecure_tls() -> bool {
+    INSECURE_TLS.get().copied().unwrap_or(false)
+}
+
+/// Whether `--json` is set; status lines then go to stderr so stdout stays parseable.
+fn json_output() -> bool {
+    JSON_OUTPUT.get().copied().unwrap_or(false)
+}
+
+fn verbosity() -> Verbosity {
+    VERBOSITY.get().copied().unwrap_or(Verbosity::Normal)
+}
+
+/// Theme for interactive prompts; plain when colors are disabled (`--no-color`, `NO_COLOR`).
+fn prompt_theme() -> Box<dyn Theme> {
+    if dialoguer::console::colors_enabled() {
+        Box::new(ColorfulTheme::default())
+    } else {
+        Box::new(SimpleTheme)
+    }
+}
+
+/// Prints an informational status line unless `--quiet` is set.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if verbosity() >= Verbosity::Normal {
+            if json_output() {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+
+/// Prints a detail line only with `--verbose`.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if verbosity() >= Verbosity::Verbose {
+            if json_output() {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 struct AppSettings {
@@ -23,6 +139,78 @@ struct AppSettings {
     cli_config_dir_from_home: String,
     cli_config_filename: String,
     cli_token_key: String,
+    /// cli.toml locations (relative to home unless absolute); the first is primary.
+    /// When empty, the location is built from the dir and filename above.
+    #[serde(default)]
+    cli_config_paths: Vec<String>,
+    /// Timeout for HTTP requests to SpacetimeDB servers
+    #[serde(default = "default_http_timeout_secs")]
+    http_timeout_secs: u64,
+    /// Proxy URL for HTTP requests; overrides the HTTP(S)_PROXY environment variables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy: Option<String>,
+    /// Profile that `switch` activates when run without a profile name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_profile: Option<String>,
+    /// Store tokens in profiles.toml encrypted with a passphrase
+    #[serde(default)]
+    encrypt_tokens: bool,
+    /// Limit profiles.toml and newly created cli.toml files to the owner (0600, Unix only)
+    #[serde(default = "default_restrict_permissions")]
+    restrict_permissions: bool,
+    /// Where profile tokens are stored
+    #[serde(default)]
+    token_backend: TokenBackend,
+    /// Record profile activations in audit.log
+    #[serde(default = "default_audit_log")]
+    audit_log: bool,
+    /// PEM file with an extra CA certificate to trust, e.g. for a self-signed
+    /// staging server (relative to home unless absolute)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert_path: Option<String>,
+    /// Characters of a token shown at each end when it is masked
+    #[serde(default = "default_mask_reveal")]
+    mask_reveal: usize,
+    /// Profile that `admin` switches to
+    #[serde(default = "default_admin_profile")]
+    admin_profile: String,
+    /// Prefer a project-local .spacetime-token/profiles.toml found from the
+    /// current directory up to the repository root
+    #[serde(default)]
+    local_profiles: bool,
+    /// Friendly names accepted wherever an address is, e.g. staging = "https://staging.example.com"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    address_aliases: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TokenBackend {
+    /// Tokens live in profiles.toml (optionally encrypted)
+    #[default]
+    File,
+    /// Tokens live in the OS keyring; profiles.toml only keeps metadata
+    Keyring,
+}
+
+fn default_http_timeout_secs() -> u64 {
+    10
+}
+
+fn default_audit_log() -> bool {
+    true
+}
+
+fn default_restrict_permissions() -> bool {
+    true
+}
+
+fn default_mask_reveal() -> usize {
+    5
+}
+
+fn default_admin_profile() -> String {
+    "admin".to_string()
 }
 
 impl Default for AppSettings {
@@ -32,6 +220,19 @@ impl Default for AppSettings {
             cli_config_dir_from_home: ".config/spacetime".to_string(),
             cli_config_filename: "cli.toml".to_string(),
             cli_token_key: "spacetimedb_token".to_string(),
+            cli_config_paths: Vec::new(),
+            http_timeout_secs: default_http_timeout_secs(),
+            proxy: None,
+            default_profile: None,
+            encrypt_tokens: false,
+            restrict_permissions: default_restrict_permissions(),
+            token_backend: TokenBackend::File,
+            audit_log: default_audit_log(),
+            mask_reveal: default_mask_reveal(),
+            admin_profile: default_admin_profile(),
+            local_profiles: false,
+            ca_cert_path: None,
+            address_aliases: BTreeMap::new(),
         }
     }
 }
@@ -45,12 +246,75 @@ impl Default for AppSettings {
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    /// Emits machine-readable JSON, including for errors (same as --output json)
+    #[clap(long, global = true, conflicts_with = "output")]
+    json: bool,
+    /// On failure, prints the JSON error object to stderr, even without --json
+    #[clap(long, global = true)]
+    json_errors: bool,
+    /// Output format for list, current, env list, status and show
+    #[clap(long, global = true, value_enum, default_value_t = OutputMode::Plain)]
+    output: OutputMode,
+    /// Prints only the bare value for current and env current, for shell prompts
+    #[clap(long, global = true, conflicts_with_all = ["json", "output"])]
+    porcelain: bool,
+    /// Prints extra detail such as resolved file paths and HTTP requests
+    #[clap(long, short, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+    /// Suppresses informational messages; command output and errors still print
+    #[clap(long, short, global = true)]
+    quiet: bool,
+    /// Disables colored output and prompts (also enabled by the NO_COLOR environment variable)
+    #[clap(long, global = true)]
+    no_color: bool,
+    /// Shows the file changes a command would make without writing them
+    #[clap(long, global = true)]
+    dry_run: bool,
+    /// Directory holding config.toml and profiles.toml (overrides SPACETIME_TOKEN_CONFIG)
+    #[clap(long, global = true, value_name = "DIR")]
+    config_dir: Option<PathBuf>,
+    /// PEM file with an extra CA certificate to trust (overrides ca_cert_path)
+    #[clap(long, global = true, value_name = "PEM")]
+    ca_cert: Option<PathBuf>,
+    /// Disables TLS certificate verification (unsafe; for debugging only)
+    #[clap(long, global = true)]
+    insecure: bool,
+    /// Targets this profile instead of the active one (show, ping, export,
+    /// copy-token); for `env use`, the profile to activate
+    #[clap(long, short, global = true, value_name = "NAME")]
+    profile: Option<String>,
+    /// Uses the project-local .spacetime-token/profiles.toml when one is found
+    #[clap(long, global = true)]
+    local: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputMode {
+    /// Human-readable text
+    #[default]
+    Plain,
+    /// Aligned columns
+    Table,
+    /// Machine-readable JSON
+    Json,
+}
+
+impl Cli {
+    fn output_mode(&self) -> OutputMode {
+        if self.json {
+            OutputMode::Json
+        } else {
+            self.output
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
 enum Commands {
     /// Saves/updates a profile with a token and sets it active
     Set(SetArgs),
+    /// Interactively asks for a profile name, address and token, then saves and activates it
+    Add,
     /// Saves the current active token from cli.toml to a new profile name
     Save(SaveArgs),
     /// Resets (clears) the profiles.toml file
@@ -66,13 +330,130 @@ enum Commands {
     /// Switches the active token to a stored profile
     Switch(SwitchArgs),
     /// Displays the current active profile name and token (masked)
-    Current,
-    /// Switches to the admin profile
+    Current(CurrentArgs),
+    /// Switches to the admin profile (admin_profile in config.toml)
     Admin,
+    /// Ends the active session in cli.toml, keeping server_configs and all profiles
+    Logout,
     /// Manage or inspect environments (server addresses)
     Env(EnvArgs),
     /// Updates the address of an existing profile
     SetAddress(SetAddressArgs),
+    /// Re-issues tokens for all profiles in an environment
+    Rotate(RotateArgs),
+    /// Re-mints the token of a server-issued profile
+    Refresh(RefreshArgs),
+    /// Backs up the profiles file, or lists and restores backups
+    Backup(BackupArgs),
+    /// Duplicates a profile under a new name
+    #[clap(alias = "clone")]
+    Copy(CopyArgs),
+    /// Prints a shell completion script to stdout
+    Completions(CompletionsArgs),
+    /// Sets or clears the note attached to a profile
+    Note(NoteArgs),
+    /// Finds profiles whose name, address, or note contains a query
+    #[clap(alias = "search")]
+    Find(FindArgs),
+    /// Runs a spacetime command as a profile without switching to it
+    Exec(ExecArgs),
+    /// Checks profiles.toml and cli.toml for inconsistencies
+    Doctor(DoctorArgs),
+    /// Rebuilds cli.toml server_configs from the stored profiles
+    Sync(SyncArgs),
+    /// Sets the profile `switch` activates when run without arguments
+    SetDefault(SetDefaultArgs),
+    /// Manages friendly names for server addresses
+    Alias(AliasArgs),
+    /// Summarizes the active environment, profile, token expiry and profile counts
+    Status,
+    /// Converts an old-format profiles file (name = "token") to the current format
+    Migrate(MigrateArgs),
+    /// Checks whether a profile's server is reachable
+    Ping(PingArgs),
+    /// Checks every stored token against its server
+    Verify(VerifyArgs),
+    /// Copies the active (or a profile's) token to the clipboard without printing it
+    CopyToken,
+    /// Opens profiles.toml in $EDITOR, then validates it and syncs cli.toml
+    Edit,
+    /// Prints profiles (including tokens) as TOML or JSON, or one profile as env/shell variables
+    Export(ExportArgs),
+    /// Shows one profile's address, token, note, timestamps and expiry
+    Show(ShowArgs),
+    /// Shows how activating a profile would change cli.toml, without writing it
+    Diff(DiffArgs),
+    /// Prints the most recent profile activations from the audit log
+    Log(LogArgs),
+    /// Restores cli.toml to how it was before the last change
+    Undo,
+    /// Deletes every profile whose token has expired
+    Prune(PruneArgs),
+    /// Adds tags to a profile
+    Tag(TagArgs),
+    /// Removes tags from a profile
+    Untag(TagArgs),
+    /// Shows which files the tool uses and the loaded settings
+    Config(ConfigArgs),
+    /// Prints stored profile names, one per line, for shell completion
+    #[clap(name = "__complete_profiles", hide = true)]
+    CompleteProfiles,
+}
+
+impl Commands {
+    /// Commands that never write profiles.toml or cli.toml and so skip the lock.
+    fn is_read_only(&self) -> bool {
+        match self {
+            Commands::List(_)
+            | Commands::Current(_)
+            | Commands::Completions(_)
+            | Commands::Find(_)
+            | Commands::Status
+            | Commands::Ping(_)
+            | Commands::Verify(_)
+            | Commands::CopyToken
+            | Commands::Export(_)
+            | Commands::Show(_)
+            | Commands::Diff(_)
+            | Commands::Log(_)
+            | Commands::Config(_)
+            | Commands::CompleteProfiles => true,
+            Commands::Doctor(args) => !args.fix,
+            Commands::Env(args) => !matches!(
+                args.command,
+                Some(EnvCommands::Use(_) | EnvCommands::Rename(_) | EnvCommands::Remove(_))
+            ),
+            Commands::Backup(args) => args.list,
+            Commands::Alias(args) => matches!(args.command, AliasCommands::List),
+            _ => false,
+        }
+    }
+
+    /// Commands that honor the global `--profile`. Apart from `env use`, which
+    /// activates the profile, they only read it.
+    fn accepts_profile_scope(&self) -> bool {
+        matches!(
+            self,
+            Commands::Show(_)
+                | Commands::Ping(_)
+                | Commands::Export(_)
+                | Commands::CopyToken
+                | Commands::Env(EnvArgs {
+                    command: Some(EnvCommands::Use(_))
+                })
+        )
+    }
+
+    /// Commands with a `--porcelain` form: `current` and `env current`.
+    fn accepts_porcelain(&self) -> bool {
+        matches!(
+            self,
+            Commands::Current(_)
+                | Commands::Env(EnvArgs {
+                    command: None | Some(EnvCommands::Current)
+                })
+        )
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -80,25 +461,191 @@ struct SetArgs {
     /// The profile name to save/update
     profile_name: String,
     /// The token to associate with the profile name
-    token: String,
+    #[clap(required_unless_present = "token_file")]
+    token: Option<String>,
+    /// Reads the token from a file instead (surrounding whitespace is trimmed)
+    #[clap(long, value_name = "PATH", conflicts_with = "token")]
+    token_file: Option<PathBuf>,
     /// The server address (e.g., 'local' or 'http://remote.host/spacetime')
     #[clap(long)]
     address: Option<String>,
+    /// Protocol to use when the address has no scheme
+    #[clap(long, value_enum)]
+    protocol: Option<AddressProtocol>,
+    /// Overwrites an existing profile without asking for confirmation
+    #[clap(long, short, visible_alias = "yes", short_alias = 'y')]
+    force: bool,
+    /// Refuses tokens that do not look like a JWT instead of only warning
+    #[clap(long)]
+    strict: bool,
+    /// Only saves the profile; cli.toml and the active session are left untouched
+    #[clap(long)]
+    no_activate: bool,
 }
 
 #[derive(Parser, Debug)]
 struct SwitchArgs {
     /// The profile name of the stored profile to make active (optional)
     profile_name: Option<String>, // Renamed
-    /// Override the environment filter with a specific address
+    /// Filters the selector by address; with a profile name, targets this address instead of
+    /// the profile's own (it must be known for the profile's token)
+    #[clap(long)]
+    address: Option<String>,
+    /// Fuzzy-matches profile names and switches to the best match
+    #[clap(long, conflicts_with = "profile_name")]
+    fuzzy: Option<String>,
+    /// Always shows the profile selector, even when a default profile is set
+    #[clap(long, conflicts_with_all = ["profile_name", "fuzzy"])]
+    pick: bool,
+    /// Switches back to the previously active profile (same as `switch -`)
+    #[clap(long, conflicts_with_all = ["profile_name", "fuzzy", "pick", "address"])]
+    previous: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ExportArgs {
+    /// Output format
+    #[clap(long, value_enum, default_value_t = ExportFormat::Toml)]
+    format: ExportFormat,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportFormat {
+    Toml,
+    Json,
+    /// `SPACETIMEDB_TOKEN=...` lines for a .env file
+    Env,
+    /// `export SPACETIMEDB_TOKEN=...` lines for `eval`
+    Sh,
+}
+
+#[derive(Parser, Debug)]
+struct ShowArgs {
+    /// The profile to show (or use the global --profile)
+    profile_name: Option<String>,
+    /// Prints the complete, unmasked token (WARNING: this prints a secret)
+    #[clap(long)]
+    full: bool,
+}
+
+#[derive(Parser, Debug)]
+struct DiffArgs {
+    /// The profile whose activation to compare against cli.toml
+    profile_name: String,
+}
+
+#[derive(Parser, Debug)]
+struct TagArgs {
+    /// The profile to tag or untag
+    profile_name: String,
+    /// One or more tags
+    #[clap(required = true)]
+    tags: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+struct PruneArgs {
+    /// Deletes without asking for confirmation
+    #[clap(long, short, visible_alias = "yes", short_alias = 'y')]
+    force: bool,
+}
+
+#[derive(Parser, Debug)]
+struct LogArgs {
+    /// Number of entries to print
+    #[clap(long, short = 'n', default_value_t = 20)]
+    lines: usize,
+}
+
+#[derive(Parser, Debug)]
+struct VerifyArgs {
+    /// Only checks the profiles for the current environment
     #[clap(long)]
+    env: bool,
+    /// Only checks the profiles with this address
+    #[clap(long, conflicts_with = "env")]
     address: Option<String>,
+    /// HTTP timeout for each request, overriding http_timeout_secs
+    #[clap(long, value_name = "SECS")]
+    timeout: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+struct PingArgs {
+    /// The profile whose server to ping (defaults to the active profile)
+    profile_name: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct SyncArgs {
+    /// Also removes server_configs entries whose nickname matches no profile
+    #[clap(long)]
+    prune: bool,
+}
+
+#[derive(Parser, Debug)]
+struct MigrateArgs {
+    /// Address given to every migrated profile
+    #[clap(long, default_value = "local")]
+    default_address: String,
+}
+
+#[derive(Parser, Debug)]
+struct AliasArgs {
+    #[clap(subcommand)]
+    command: AliasCommands,
+}
+
+#[derive(Parser, Debug)]
+enum AliasCommands {
+    /// Adds or replaces an alias for a server address
+    Add(AliasAddArgs),
+    /// Lists the configured aliases
+    List,
+    /// Removes an alias
+    Remove(AliasRemoveArgs),
+}
+
+#[derive(Parser, Debug)]
+struct AliasAddArgs {
+    /// The friendly name, e.g. 'staging'
+    name: String,
+    /// The address it stands for (e.g., 'https://staging.example.com')
+    url: String,
+}
+
+#[derive(Parser, Debug)]
+struct AliasRemoveArgs {
+    /// The alias to remove
+    name: String,
+}
+
+#[derive(Parser, Debug)]
+struct SetDefaultArgs {
+    /// The profile `switch` should activate when run without arguments
+    #[clap(required_unless_present = "clear")]
+    profile_name: Option<String>,
+    /// Removes the configured default profile
+    #[clap(long, conflicts_with = "profile_name")]
+    clear: bool,
 }
 
 #[derive(Parser, Debug)]
 struct SaveArgs {
     /// The profile name to save the current active token under
     profile_name: String, // Renamed
+    /// Replaces the profile if it already exists
+    #[clap(long)]
+    overwrite: bool,
+    /// With --overwrite, replaces the profile without confirmation
+    #[clap(
+        long,
+        short,
+        visible_alias = "yes",
+        short_alias = 'y',
+        requires = "overwrite"
+    )]
+    force: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -108,6 +655,42 @@ struct CreateArgs {
     /// The server address (e.g., 'local' or 'http://remote.host/spacetime')
     #[clap(long)]
     address: Option<String>,
+    /// Protocol to use when the address has no scheme
+    #[clap(long, value_enum)]
+    protocol: Option<AddressProtocol>,
+    /// Skips checking the new token against the server after creation
+    #[clap(long)]
+    no_verify: bool,
+    /// Verifies the token before saving and aborts if the server rejects it
+    #[clap(long, conflicts_with = "no_verify")]
+    verify: bool,
+    /// Skips the confirmation before logging out of the current session
+    #[clap(long, short, visible_alias = "yes", short_alias = 'y')]
+    force: bool,
+    /// Stays logged out if the login fails, instead of restoring the previous session
+    #[clap(long)]
+    force_logout: bool,
+    /// Registers this existing token instead of logging in
+    #[clap(long)]
+    token: Option<String>,
+    /// Registers the token read from a file instead of logging in
+    #[clap(long, value_name = "PATH", conflicts_with = "token")]
+    token_file: Option<PathBuf>,
+    /// How to obtain the token (defaults to server-issued)
+    #[clap(long, value_enum, conflicts_with_all = ["token", "token_file"])]
+    login_mode: Option<LoginMode>,
+    /// HTTP timeout in seconds for this command, overriding http_timeout_secs
+    #[clap(long, value_name = "SECS")]
+    timeout: Option<u64>,
+}
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LoginMode {
+    /// `spacetime login --server-issued-login` for local, a direct identity request otherwise
+    ServerIssued,
+    /// The interactive browser login of `spacetime login`
+    Browser,
 }
 
 #[derive(Parser, Debug)]
@@ -115,15 +698,57 @@ struct ListArgs {
     /// Only show profiles for the current environment
     #[clap(long)]
     env: bool,
+    /// Only show profiles with this address
+    #[clap(long, conflicts_with = "env")]
+    address: Option<String>,
+    /// Only show profiles whose token has expired
+    #[clap(long)]
+    stale: bool,
+    /// With --stale, also show profiles whose token has no decodable expiry
+    #[clap(long, requires = "stale")]
+    include_unknown: bool,
+    /// Only show profiles carrying this tag
+    #[clap(long)]
+    tag: Option<String>,
+    /// Orders the profiles by name, address, or most recent use
+    #[clap(long, value_enum, default_value_t = ListSort::Name)]
+    sort: ListSort,
+    /// With --json, wraps the profiles in an object with a `count` field
+    #[clap(long)]
+    count: bool,
+    /// Prints only the distinct addresses of the listed profiles, one per line
+    #[clap(long, conflicts_with = "count")]
+    addresses: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ListSort {
+    Name,
+    Address,
+    LastUsed,
+}
+
+#[derive(Parser, Debug)]
+struct CurrentArgs {
+    /// Prints the complete, unmasked token (WARNING: this prints a secret)
+    #[clap(long, visible_alias = "show-token")]
+    full: bool,
 }
 
 #[derive(Parser, Debug)]
 struct DeleteArgs {
     /// The profile name of the profile to delete
-    profile_name: String,
+    #[clap(required_unless_present = "env")]
+    profile_name: Option<String>,
+    /// Deletes every profile whose address matches this environment
+    #[clap(long, value_name = "ADDRESS", conflicts_with = "profile_name")]
+    env: Option<String>,
     /// Forces deletion without confirmation
-    #[clap(long, short)]
+    #[clap(long, short, visible_alias = "yes", short_alias = 'y')]
     force: bool,
+    /// Activates this profile if the deleted one is the active profile
+    #[clap(long, value_name = "NAME", conflicts_with = "env")]
+    switch_to: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -136,9 +761,150 @@ struct ResetArgs {
 #[derive(Parser, Debug)]
 struct SetAddressArgs {
     /// The profile name to update
-    profile_name: String,
+    #[clap(required_unless_present = "all_matching")]
+    profile_name: Option<String>,
     /// The new server address
-    address: String,
+    #[clap(required_unless_present = "all_matching")]
+    address: Option<String>,
+    /// Moves every profile using the OLD address to NEW
+    #[clap(
+        long,
+        num_args = 2,
+        value_names = ["OLD", "NEW"],
+        conflicts_with_all = ["profile_name", "address"]
+    )]
+    all_matching: Option<Vec<String>>,
+    /// Protocol to use when the address has no scheme
+    #[clap(long, value_enum)]
+    protocol: Option<AddressProtocol>,
+    /// Skips the confirmation when moving several profiles
+    #[clap(long, short, visible_alias = "yes", short_alias = 'y')]
+    force: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AddressProtocol {
+    Http,
+    Https,
+}
+
+#[derive(Parser, Debug)]
+struct RefreshArgs {
+    /// The profile whose token to re-mint
+    profile_name: String,
+    /// Re-mints a profile that was not created via server-issued login without asking
+    #[clap(long, short, visible_alias = "yes", short_alias = 'y')]
+    force: bool,
+}
+
+#[derive(Parser, Debug)]
+#[clap(group(clap::ArgGroup::new("target").required(true).args(["env", "address", "all"])))]
+struct RotateArgs {
+    /// Rotate profiles for the current environment
+    #[clap(long)]
+    env: bool,
+    /// Rotate profiles that use this server address
+    #[clap(long)]
+    address: Option<String>,
+    /// Rotate every stored profile
+    #[clap(long)]
+    all: bool,
+    /// Keep rotating the remaining profiles when one fails
+    #[clap(long)]
+    continue_on_error: bool,
+}
+
+#[derive(Parser, Debug)]
+struct BackupArgs {
+    #[clap(subcommand)]
+    command: Option<BackupCommands>,
+    /// Lists existing backups instead of creating a new one
+    #[clap(long)]
+    list: bool,
+}
+
+#[derive(Parser, Debug)]
+enum BackupCommands {
+    /// Restores a backup over the live profiles file
+    Restore(BackupRestoreArgs),
+}
+
+#[derive(Parser, Debug)]
+struct BackupRestoreArgs {
+    /// The backup file name (in the config directory) or path to restore
+    file: String,
+    /// Forces restore without confirmation
+    #[clap(long, short)]
+    force: bool,
+}
+
+#[derive(Parser, Debug)]
+struct CopyArgs {
+    /// The profile name to copy from
+    source: String,
+    /// The new profile name
+    dest: String,
+    /// Overrides the server address of the copy
+    #[clap(long)]
+    address: Option<String>,
+    /// Protocol to use when --address has no scheme
+    #[clap(long, value_enum, requires = "address")]
+    protocol: Option<AddressProtocol>,
+    /// Sets the copied profile as active in cli.toml
+    #[clap(long)]
+    activate: bool,
+}
+
+#[derive(Parser, Debug)]
+struct CompletionsArgs {
+    /// The shell to generate completions for
+    #[clap(value_enum)]
+    shell: clap_complete::Shell,
+    /// Writes the script to the shell's completion directory instead of printing it
+    #[clap(long)]
+    install: bool,
+    /// With --install, replaces an existing completion script
+    #[clap(long, short, requires = "install")]
+    force: bool,
+}
+
+#[derive(Parser, Debug)]
+struct NoteArgs {
+    /// The profile name to annotate
+    profile_name: String,
+    /// The note text; read from stdin if omitted. An empty note clears it
+    text: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct FindArgs {
+    /// Case-insensitive text to search for
+    query: String,
+    /// Only match against profile names
+    #[clap(long)]
+    name_only: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ExecArgs {
+    /// The profile to run the command as
+    profile_name: String,
+    /// Extra environment variable for the spawned command (repeatable)
+    #[clap(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    envs: Vec<(String, String)>,
+    /// Arguments passed to `spacetime` (after `--`)
+    #[clap(last = true, required = true)]
+    args: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+struct DoctorArgs {
+    /// Repairs what can be derived from the stored profiles
+    #[clap(long)]
+    fix: bool,
+    /// With --fix, applies address repairs that need confirmation without asking
+    #[clap(long, short, visible_alias = "force", requires = "fix")]
+    yes: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -155,15 +921,47 @@ enum EnvCommands {
     List,
     /// Set the active environment and optionally switch to a matching profile
     Use(EnvUseArgs),
+    /// Change the address of every profile in an environment
+    Rename(EnvRenameArgs),
+    /// Forget an environment: drop its server_configs entries and clear it as default
+    Remove(EnvRemoveArgs),
+}
+
+#[derive(Parser, Debug)]
+struct ConfigArgs {
+    #[clap(subcommand)]
+    command: ConfigCommands,
+}
+
+#[derive(Parser, Debug)]
+enum ConfigCommands {
+    /// Print the resolved config directory, profiles file and cli.toml paths
+    Path,
+    /// Print the settings loaded from config.toml, including defaults
+    Show,
+}
+
+#[derive(Parser, Debug)]
+struct EnvRenameArgs {
+    /// The current address of the environment
+    old: String,
+    /// The address to move its profiles to
+    new: String,
+}
+
+#[derive(Parser, Debug)]
+struct EnvRemoveArgs {
+    /// The address of the environment to forget
+    address: String,
+    /// Also deletes the profiles that still use the address, without confirmation
+    #[clap(long, short, visible_alias = "yes", short_alias = 'y')]
+    force: bool,
 }
 
 #[derive(Parser, Debug)]
 struct EnvUseArgs {
     /// The address to set as the current environment
     address: String,
-    /// The profile to activate while setting the environment
-    #[clap(long, short)]
-    profile: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -173,32 +971,185 @@ struct IdentityResponse {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Profile {
+    /// Empty in profiles.toml when the token lives in the OS keyring
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     token: String,
     address: String,
+    /// RFC3339 time the profile was first stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    /// RFC3339 time the profile was last made active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used: Option<String>,
+    /// Free-form description, e.g. "prod readonly"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    /// Addresses this profile used before its current one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    previous_addresses: Vec<String>,
+    /// Labels for grouping profiles, e.g. a team or customer
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// How `create` obtained the token; unset for tokens stored by hand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    login_mode: Option<LoginMode>,
+    /// Environment variable `token` was resolved from, written back as `${ENV:NAME}`
+    #[serde(skip)]
+    token_env: Option<String>,
+}
+
+impl Profile {
+    fn new(token: String, address: String) -> Self {
+        Self {
+            token,
+            address,
+            created_at: Some(now_rfc3339()),
+            last_used: None,
+            note: None,
+            previous_addresses: Vec::new(),
+            tags: Vec::new(),
+            login_mode: None,
+            token_env: None,
+        }
+    }
+
+    /// Changes the address, remembering the old one for `switch --address`.
+    fn set_address(&mut self, address: String) {
+        if self.address != address {
+            let previous = std::mem::replace(&mut self.address, address);
+            self.previous_addresses
+                .retain(|known| known != &self.address);
+            if !self.previous_addresses.contains(&previous) {
+                self.previous_addresses.push(previous);
+            }
+        }
+    }
+}
+
+/// Reads a token stored in a file, e.g. by secrets tooling, trimming surrounding whitespace.
+fn read_token_file(path: &Path) -> Result<String> {
+    let token = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read token file {:?}", path))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        anyhow::bail!("Token file {:?} is empty.", path);
+    }
+    Ok(token)
+}
+
+/// Quotes `value` for POSIX shells (and .env files) using single quotes.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn now_rfc3339() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Default, Clone)]
 struct UserProfiles(HashMap<String, Profile>);
 
-fn get_app_config_dir() -> Result<PathBuf> {
-    let config_dir = dirs::config_dir()
+// Written sorted by name so the file (and `--dry-run` diffs) stay stable between writes.
+impl Serialize for UserProfiles {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0
+            .iter()
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+}
+
+/// Per-profile outcome of a bulk operation.
+#[derive(Debug, Default)]
+struct OperationSummary {
+    succeeded: Vec<String>,
+    failed: Vec<(String, String)>,
+}
+
+impl OperationSummary {
+    fn record_success(&mut self, profile_name: &str) {
+        self.succeeded.push(profile_name.to_string());
+    }
+
+    fn record_failure(&mut self, profile_name: &str, error: &anyhow::Error) {
+        self.failed
+            .push((profile_name.to_string(), format!("{:#}", error)));
+    }
+
+    fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+
+    fn print(&self, action: &str) {
+        println!(
+            "{} {} profile(s), {} failed.",
+            action,
+            self.succeeded.len(),
+            self.failed.len()
+        );
+        for name in &self.succeeded {
+            println!("- {}: ok", name);
+        }
+        for (name, error) in &self.failed {
+            println!("- {}: FAILED ({})", name, error);
+        }
+    }
+}
+
+/// The app directory: `--config-dir`, else `SPACETIME_TOKEN_CONFIG` (or `SPACETIME_TOKEN_HOME`),
+/// else `spacetime-token` under the user's config directory.
+fn resolve_app_config_dir() -> Result<PathBuf> {
+    if let Some(dir) = CONFIG_DIR_OVERRIDE.get() {
+        return Ok(dir.clone());
+    }
+    for var in CONFIG_DIR_ENV_VARS {
+        if let Some(dir) = std::env::var_os(var).filter(|dir| !dir.is_empty()) {
+            return Ok(PathBuf::from(dir));
+        }
+    }
+    Ok(dirs::config_dir()
         .context("Failed to get user's config directory.")?
-        .join(APP_DIR_NAME);
+        .join(APP_DIR_NAME))
+}
+
+fn get_app_config_dir() -> Result<PathBuf> {
+    let config_dir = resolve_app_config_dir()?;
     if !config_dir.exists() {
         fs::create_dir_all(&config_dir).with_context(|| {
             format!("Failed to create app config directory at {:?}", config_dir)
         })?;
-        println!("Created application config directory at {:?}", config_dir);
+        info!("Created application config directory at {:?}", config_dir);
     }
     Ok(config_dir)
 }
 
-fn load_app_settings() -> Result<AppSettings> {
-    let app_config_dir = get_app_config_dir()?;
+/// Takes an exclusive advisory lock on a file in the app config directory so
+/// concurrent mutating invocations run one after another.
+fn lock_app_config_dir() -> Result<fs::File> {
+    let lock_path = get_app_config_dir()?.join(LOCK_FILENAME);
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open lock file {:?}", lock_path))?;
+    if lock_file.try_lock_exclusive().is_err() {
+        info!("Waiting for another spacetime-token command to finish...");
+        lock_file
+            .lock_exclusive()
+            .with_context(|| format!("Failed to lock {:?}", lock_path))?;
+    }
+    verbose!("Locked {:?}", lock_path);
+    Ok(lock_file)
+}
+
+fn load_app_settings() -> Result<AppSettings> {
+    let app_config_dir = get_app_config_dir()?;
     let config_file_path = app_config_dir.join(DEFAULT_CONFIG_FILENAME);
 
     if !config_file_path.exists() {
-        println!(
+        info!(
             "Configuration file not found at {:?}. Creating with default settings.",
             config_file_path
         );
@@ -210,6 +1161,7 @@ fn load_app_settings() -> Result<AppSettings> {
         return Ok(default_settings);
     }
 
+    verbose!("Loading settings from {:?}", config_file_path);
     let content = fs::read_to_string(&config_file_path)
         .with_context(|| format!("Failed to read app config file at {:?}", config_file_path))?;
     toml::from_str(&content)
@@ -221,27 +1173,69 @@ fn write_app_settings(settings: &AppSettings) -> Result<()> {
     let config_file_path = app_config_dir.join(DEFAULT_CONFIG_FILENAME);
     let toml_content =
         toml::to_string_pretty(settings).context("Failed to serialize app settings to TOML")?;
+    if dry_run() {
+        preview_file_write(&config_file_path, &toml_content);
+        return Ok(());
+    }
     fs::write(&config_file_path, toml_content)
         .with_context(|| format!("Failed to write app config to {:?}", config_file_path))?;
-    println!("Configuration saved to {:?}", config_file_path);
+    info!("Configuration saved to {:?}", config_file_path);
     Ok(())
 }
 
 fn get_profiles_filepath(settings: &AppSettings) -> Result<PathBuf> {
     // Renamed function
+    if settings.local_profiles {
+        if let Some(path) = find_local_profiles_file(settings)? {
+            return Ok(path);
+        }
+    }
     let app_config_dir = get_app_config_dir()?;
     Ok(app_config_dir.join(&settings.profiles_filename)) // Renamed field
 }
 
-fn get_cli_toml_path(settings: &AppSettings) -> Result<PathBuf> {
+/// Looks for `.spacetime-token/<profiles_filename>` in the current directory and
+/// its parents, stopping at the first directory containing `.git`.
+fn find_local_profiles_file(settings: &AppSettings) -> Result<Option<PathBuf>> {
+    let current_dir = std::env::current_dir().context("Failed to get current directory")?;
+    for dir in current_dir.ancestors() {
+        let candidate = dir
+            .join(LOCAL_PROFILES_DIR)
+            .join(&settings.profiles_filename);
+        if candidate.is_file() {
+            return Ok(Some(candidate));
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+fn get_cli_toml_paths(settings: &AppSettings) -> Result<Vec<PathBuf>> {
     let home_dir = dirs::home_dir().context("Failed to get home directory")?;
-    Ok(home_dir
-        .join(&settings.cli_config_dir_from_home)
-        .join(&settings.cli_config_filename))
+    if settings.cli_config_paths.is_empty() {
+        return Ok(vec![home_dir
+            .join(&settings.cli_config_dir_from_home)
+            .join(&settings.cli_config_filename)]);
+    }
+    Ok(settings
+        .cli_config_paths
+        .iter()
+        .map(|path| home_dir.join(path))
+        .collect())
+}
+
+fn get_cli_toml_path(settings: &AppSettings) -> Result<PathBuf> {
+    get_cli_toml_paths(settings)?
+        .into_iter()
+        .next()
+        .context("No cli.toml location configured.")
 }
 
 fn read_profiles(settings: &AppSettings) -> Result<UserProfiles> {
     let profiles_path = get_profiles_filepath(settings)?;
+    verbose!("Reading profiles from {:?}", profiles_path);
     if !profiles_path.exists() {
         fs::write(&profiles_path, "").with_context(|| {
             format!(
@@ -249,7 +1243,8 @@ fn read_profiles(settings: &AppSettings) -> Result<UserProfiles> {
                 profiles_path
             )
         })?;
-        println!("Created empty {}.", settings.profiles_filename);
+        restrict_file_permissions(settings, &profiles_path)?;
+        info!("Created empty {}.", settings.profiles_filename);
         return Ok(UserProfiles::default());
     }
 
@@ -260,86 +1255,704 @@ fn read_profiles(settings: &AppSettings) -> Result<UserProfiles> {
     }
 
     // Try parsing new format first
-    match toml::from_str::<UserProfiles>(&content) {
-        Ok(profiles) => Ok(profiles),
+    let mut profiles = match toml::from_str::<UserProfiles>(&content) {
+        Ok(profiles) => profiles,
         Err(e) => {
             // If it fails, try parsing the old format and migrating
-            println!(
-                "Could not parse profiles file. Assuming old format and attempting migration..."
-            );
+            info!("Could not parse profiles file. Assuming old format and attempting migration...");
 
-            #[derive(Deserialize)]
-            struct OldUserProfiles(HashMap<String, String>);
-
-            match toml::from_str::<OldUserProfiles>(&content) {
-                Ok(old_profiles) => {
-                    let mut new_profiles = UserProfiles::default();
-                    for (name, token) in old_profiles.0 {
-                        new_profiles.0.insert(
-                            name,
-                            Profile {
-                                token,
-                                address: "local".to_string(),
-                            },
-                        );
-                    }
+            match migrate_old_profiles(&content, "local") {
+                Ok(new_profiles) => {
                     // Write the migrated profiles back to the file
                     write_profiles(settings, &new_profiles)
                         .context("Failed to save migrated profiles file.")?;
-                    println!("Successfully migrated profiles to new format.");
-                    Ok(new_profiles)
+                    info!("Successfully migrated profiles to new format.");
+                    return Ok(new_profiles);
                 }
                 Err(migration_err) => {
                     println!(
                         "Failed to parse profiles file as old format either: {}",
                         migration_err
                     );
-                    Err(anyhow::Error::new(e).context(format!(
+                    return Err(anyhow::Error::new(e).context(format!(
                         "Failed to parse profiles file at {:?}. It might be corrupted.",
                         profiles_path
-                    )))
+                    )));
                 }
             }
         }
+    };
+
+    let use_keyring = settings.token_backend == TokenBackend::Keyring;
+    let mut tokens_to_migrate = 0;
+    for (name, profile) in profiles.0.iter_mut() {
+        if let Some(var) = env_token_reference(&profile.token).map(str::to_string) {
+            // An unset variable leaves the reference in place; activating the profile reports it.
+            if let Some(value) = std::env::var(&var).ok().filter(|value| !value.is_empty()) {
+                profile.token = value;
+                profile.token_env = Some(var);
+            }
+        } else if profile.token.is_empty() {
+            if use_keyring {
+                profile.token = read_keyring_token(name)?;
+            }
+        } else if profile.token.starts_with(ENCRYPTED_TOKEN_PREFIX) {
+            if dry_run() {
+                // Kept as stored, so a dry run never asks for the passphrase.
+                continue;
+            }
+            profile.token = decrypt_token(&profile.token)?;
+            if use_keyring {
+                tokens_to_migrate += 1;
+            }
+        } else if use_keyring || settings.encrypt_tokens {
+            tokens_to_migrate += 1;
+        }
+    }
+    // Rewrite the file in place when tokens are stored differently than configured
+    if tokens_to_migrate > 0 {
+        write_profiles(settings, &profiles).context("Failed to save migrated profiles file.")?;
+        let storage = match (use_keyring, settings.encrypt_tokens) {
+            (true, _) => "the system keyring".to_string(),
+            (false, true) => format!("encrypted storage in {}", settings.profiles_filename),
+            (false, false) => settings.profiles_filename.clone(),
+        };
+        info!("Migrated {} token(s) to {}.", tokens_to_migrate, storage);
+    }
+    Ok(profiles)
+}
+
+/// The variable name in a `${ENV:NAME}` token reference.
+fn env_token_reference(token: &str) -> Option<&str> {
+    token
+        .strip_prefix(ENV_TOKEN_PREFIX)?
+        .strip_suffix('}')
+        .filter(|name| !name.is_empty())
+}
+
+/// Fails for a profile whose `${ENV:NAME}` token could not be resolved.
+fn ensure_token_resolved(profile_name: &str, profile: &Profile) -> Result<()> {
+    if let Some(var) = env_token_reference(&profile.token) {
+        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+            "Profile '{}' takes its token from the environment variable {}, which is not set.",
+            profile_name, var
+        )));
+    }
+    Ok(())
+}
+
+/// Converts the original `name = "token"` profiles format, giving every profile
+/// `default_address`.
+fn migrate_old_profiles(
+    content: &str,
+    default_address: &str,
+) -> Result<UserProfiles, toml::de::Error> {
+    #[derive(Deserialize)]
+    struct OldUserProfiles(HashMap<String, String>);
+
+    let old_profiles = toml::from_str::<OldUserProfiles>(content)?;
+    let mut new_profiles = UserProfiles::default();
+    for (name, token) in old_profiles.0 {
+        new_profiles.0.insert(
+            name,
+            Profile {
+                token,
+                address: default_address.to_string(),
+                created_at: None,
+                last_used: None,
+                note: None,
+                previous_addresses: Vec::new(),
+                tags: Vec::new(),
+                login_mode: None,
+                token_env: None,
+            },
+        );
+    }
+    Ok(new_profiles)
+}
+
+/// Returns the token passphrase, reading it from `SPACETIME_TOKEN_PASSPHRASE` or
+/// prompting once per invocation.
+fn token_passphrase(confirm: bool) -> Result<String> {
+    if let Some(passphrase) = TOKEN_PASSPHRASE.get() {
+        return Ok(passphrase.clone());
+    }
+    let passphrase = match std::env::var(PASSPHRASE_ENV_VAR) {
+        Ok(passphrase) if !passphrase.is_empty() => passphrase,
+        _ => {
+            let theme = prompt_theme();
+            let prompt = Password::with_theme(theme.as_ref()).with_prompt("Token passphrase");
+            let prompt = if confirm {
+                prompt.with_confirmation("Confirm passphrase", "Passphrases do not match.")
+            } else {
+                prompt
+            };
+            prompt
+                .interact()
+                .context("Failed to read token passphrase")?
+        }
+    };
+    Ok(TOKEN_PASSPHRASE.get_or_init(|| passphrase).clone())
+}
+
+/// Derives (and caches) the 256-bit token key for `salt` with Argon2.
+fn token_key(salt: &[u8; TOKEN_SALT_LEN], confirm: bool) -> Result<[u8; 32]> {
+    let mut keys = TOKEN_KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(key) = keys.get(salt) {
+        return Ok(*key);
+    }
+    let passphrase = token_passphrase(confirm)?;
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive token key: {}", e))?;
+    keys.insert(*salt, key);
+    Ok(key)
+}
+
+/// Encrypts a token as `enc:v1:<base64(salt || nonce || ciphertext)>`.
+fn encrypt_token(token: &str) -> Result<String> {
+    let salt = *TOKEN_WRITE_SALT.get_or_init(|| {
+        let mut salt = [0u8; TOKEN_SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    });
+    // Only ask for confirmation when no passphrase has been entered yet
+    let key = token_key(&salt, TOKEN_PASSPHRASE.get().is_none())?;
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, token.as_bytes())
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt token"))?;
+
+    let mut blob = salt.to_vec();
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+    let engine = base64::engine::general_purpose::STANDARD;
+    Ok(format!("{}{}", ENCRYPTED_TOKEN_PREFIX, engine.encode(blob)))
+}
+
+fn decrypt_token(stored: &str) -> Result<String> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let blob = engine
+        .decode(stored.trim_start_matches(ENCRYPTED_TOKEN_PREFIX))
+        .context("Encrypted token is not valid base64")?;
+    if blob.len() < TOKEN_SALT_LEN + TOKEN_NONCE_LEN {
+        anyhow::bail!("Encrypted token is truncated.");
+    }
+    let (salt, rest) = blob.split_at(TOKEN_SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(TOKEN_NONCE_LEN);
+    let salt: [u8; TOKEN_SALT_LEN] = salt.try_into().expect("salt length checked above");
+
+    let key = token_key(&salt, false)?;
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt token. Is the passphrase correct?"))?;
+    String::from_utf8(plaintext).context("Decrypted token is not valid UTF-8")
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it into place,
+/// so an interrupted write never leaves `path` partially overwritten.
+fn write_file_atomically(path: &Path, contents: &str) -> Result<()> {
+    if dry_run() {
+        preview_file_write(path, contents);
+        return Ok(());
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid file path {:?}", path))?;
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    let result = (|| -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // Owner-only from the start, so tokens are never briefly readable by others.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&temp_path)
+            .with_context(|| format!("Failed to create temporary file {:?}", temp_path))?;
+        file.write_all(contents.as_bytes())
+            .with_context(|| format!("Failed to write temporary file {:?}", temp_path))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to flush temporary file {:?}", temp_path))?;
+        // The replacement keeps the mode of the file it replaces.
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temp_path, metadata.permissions())
+                .with_context(|| format!("Failed to set permissions of {:?}", temp_path))?;
+        }
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to move {:?} into place at {:?}", temp_path, path))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Limits `path` to owner read/write (0600), since it holds tokens. Only on Unix,
+/// and only while `restrict_permissions` is on.
+fn restrict_file_permissions(settings: &AppSettings, path: &Path) -> Result<()> {
+    if !settings.restrict_permissions || dry_run() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict permissions of {:?}", path))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// The Unix mode of `path` when group or others may access it.
+fn loose_file_mode(path: &Path) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path).ok()?.permissions().mode() & 0o777;
+        (mode & 0o077 != 0).then_some(mode)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Files that hold tokens: profiles.toml, the cli.toml files, the profiles backups
+/// and the cli.toml snapshot kept for `undo`.
+fn token_file_paths(settings: &AppSettings) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![get_profiles_filepath(settings)?];
+    paths.extend(get_cli_toml_paths(settings)?);
+    paths.extend(list_profile_backups(settings)?);
+    paths.push(cli_toml_snapshot_path(settings)?);
+    Ok(paths)
+}
+
+/// Doctor warnings for token files readable by group or others.
+fn permission_findings(settings: &AppSettings) -> Result<Vec<DoctorFinding>> {
+    let mut findings = Vec::new();
+    if !settings.restrict_permissions {
+        return Ok(findings);
+    }
+    for path in token_file_paths(settings)? {
+        if let Some(mode) = loose_file_mode(&path) {
+            findings.push(DoctorFinding::warning(format!(
+                "{:?} holds tokens but is accessible by group or others (mode {:o}). Run 'doctor --fix' or 'chmod 600' on it.",
+                path, mode
+            )));
+        }
+    }
+    Ok(findings)
+}
+
+/// Prints a unified diff of `path`'s current content against `contents` for `--dry-run`.
+/// Token values are masked on both sides.
+fn preview_file_write(path: &Path, contents: &str) {
+    let current = fs::read_to_string(path).unwrap_or_default();
+    if current == contents {
+        println!("(dry run) {:?} would be unchanged.", path);
+        return;
+    }
+    let reveal = MASK_REVEAL
+        .get()
+        .copied()
+        .unwrap_or_else(default_mask_reveal);
+    let current = mask_toml_tokens(&current, reveal);
+    let contents = mask_toml_tokens(contents, reveal);
+    let label = path.display().to_string();
+    let diff = similar::TextDiff::from_lines(current.as_str(), contents.as_str());
+    print!(
+        "{}",
+        diff.unified_diff()
+            .context_radius(3)
+            .header(&format!("{} (current)", label), &format!("{} (new)", label))
+    );
+}
+
+/// Masks the string value of every `key = "..."` line whose key ends in `token`,
+/// leaving `${ENV:NAME}` references readable.
+fn mask_toml_tokens(text: &str, reveal: usize) -> String {
+    let mut masked = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let value = body.find('=').and_then(|eq| {
+            let key = body[..eq].trim().trim_matches('"');
+            let start = eq + 1 + body[eq + 1..].find('"')?;
+            let end = body.rfind('"')?;
+            let quoted_alone = body[eq + 1..start].trim().is_empty()
+                && matches!(
+                    body[end + 1..].trim_start().chars().next(),
+                    None | Some('#')
+                )
+                && end > start;
+            (key.ends_with("token") && quoted_alone).then_some((start + 1, end))
+        });
+        match value {
+            Some((start, end)) if env_token_reference(&body[start..end]).is_none() => {
+                masked.push_str(&line[..start]);
+                masked.push_str(&mask_token(&body[start..end], reveal));
+                masked.push_str(&line[end..]);
+            }
+            _ => masked.push_str(line),
+        }
     }
+    masked
+}
+
+/// The "Successfully updated ..." confirmation after a write. Like every status
+/// line it is silenced by `--quiet`; command output such as `list` is not.
+fn report_file_updated(description: &str) {
+    info!("Successfully updated {}.", description);
 }
 
 fn write_profiles(settings: &AppSettings, profiles: &UserProfiles) -> Result<()> {
     // Renamed function and param
     let profiles_path = get_profiles_filepath(settings)?; // Renamed variable
-    let content =
-        toml::to_string_pretty(profiles).context("Failed to serialize profiles data to TOML")?; // Renamed
-    fs::write(&profiles_path, content) // Renamed variable
+    verbose!("Writing profiles to {:?}", profiles_path);
+    let use_keyring = settings.token_backend == TokenBackend::Keyring;
+    let previous_names = if use_keyring {
+        stored_profile_names(&profiles_path)
+    } else {
+        Vec::new()
+    };
+    let mut stored_profiles = profiles.clone();
+    for (name, profile) in stored_profiles.0.iter_mut() {
+        // Keep the reference unless a command replaced the token.
+        if let Some(var) = &profile.token_env {
+            if std::env::var(var).ok().as_deref() == Some(profile.token.as_str()) {
+                profile.token = format!("{}{}}}", ENV_TOKEN_PREFIX, var);
+                continue;
+            }
+        }
+        if env_token_reference(&profile.token).is_some() {
+            continue;
+        }
+        if use_keyring {
+            if !dry_run() {
+                write_keyring_token(name, &profile.token)?;
+            }
+            profile.token.clear();
+        } else if settings.encrypt_tokens && !dry_run() {
+            // A dry run does not ask for the passphrase; the preview masks the token anyway.
+            profile.token = encrypt_token(&profile.token)?;
+        }
+    }
+    let content = toml::to_string_pretty(&stored_profiles)
+        .context("Failed to serialize profiles data to TOML")?; // Renamed
+    write_file_atomically(&profiles_path, &content) // Renamed variable
         .with_context(|| format!("Failed to write profiles file at {:?}", profiles_path))?; // Renamed
-    println!("Successfully updated {}.", settings.profiles_filename); // Renamed field
+    restrict_file_permissions(settings, &profiles_path)?;
+    if dry_run() {
+        return Ok(());
+    }
+    report_file_updated(&settings.profiles_filename);
+
+    // Drop keyring entries of profiles that were deleted (delete, reset, ...), unless
+    // a backup still lists them, so `backup restore` brings back working tokens.
+    let removed: Vec<String> = previous_names
+        .into_iter()
+        .filter(|name| !profiles.0.contains_key(name))
+        .collect();
+    if !removed.is_empty() {
+        let backed_up: BTreeSet<String> = list_profile_backups(settings)?
+            .iter()
+            .flat_map(|backup| stored_profile_names(backup))
+            .collect();
+        for name in removed.iter().filter(|name| !backed_up.contains(*name)) {
+            delete_keyring_token(name)?;
+        }
+    }
     Ok(())
 }
 
+/// Profile names currently in the profiles file, without touching their tokens.
+fn stored_profile_names(profiles_path: &Path) -> Vec<String> {
+    fs::read_to_string(profiles_path)
+        .ok()
+        .and_then(|content| content.parse::<toml::Table>().ok())
+        .map(|table| table.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+fn keyring_entry(profile_name: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, profile_name).with_context(|| {
+        format!(
+            "Failed to open keyring entry for profile '{}'",
+            profile_name
+        )
+    })
+}
+
+fn read_keyring_token(profile_name: &str) -> Result<String> {
+    keyring_entry(profile_name)?
+        .get_password()
+        .with_context(|| {
+            format!(
+                "Failed to read token for profile '{}' from the system keyring",
+                profile_name
+            )
+        })
+}
+
+fn write_keyring_token(profile_name: &str, token: &str) -> Result<()> {
+    keyring_entry(profile_name)?
+        .set_password(token)
+        .with_context(|| {
+            format!(
+                "Failed to store token for profile '{}' in the system keyring",
+                profile_name
+            )
+        })
+}
+
+fn delete_keyring_token(profile_name: &str) -> Result<()> {
+    match keyring_entry(profile_name)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Failed to remove token for profile '{}' from the system keyring",
+                profile_name
+            )
+        }),
+    }
+}
+
+fn backup_profiles_file(settings: &AppSettings) -> Result<Option<PathBuf>> {
+    let profiles_path = get_profiles_filepath(settings)?;
+    if !profiles_path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&profiles_path)
+        .with_context(|| format!("Failed to read profiles file at {:?}", profiles_path))?;
+    if content.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let stem = Path::new(&settings.profiles_filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("profiles");
+    // Microseconds, so backups taken within the same second do not overwrite each other.
+    let timestamp = chrono::Utc::now()
+        .format("%Y-%m-%dT%H-%M-%S%.6fZ")
+        .to_string();
+    let app_config_dir = get_app_config_dir()?;
+    if dry_run() {
+        let backup_path = app_config_dir.join(backup_file_name(stem, &timestamp, 0));
+        println!(
+            "(dry run) Would back up {} to {:?}.",
+            settings.profiles_filename, backup_path
+        );
+        return Ok(Some(backup_path));
+    }
+    // Never replaces an existing backup, even if the clock yields the same timestamp twice.
+    let mut attempt = 0;
+    let backup_path = loop {
+        let backup_path = app_config_dir.join(backup_file_name(stem, &timestamp, attempt));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&backup_path)
+        {
+            Ok(mut file) => {
+                file.write_all(content.as_bytes()).with_context(|| {
+                    format!("Failed to write profiles backup to {:?}", backup_path)
+                })?;
+                break backup_path;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to create profiles backup {:?}", backup_path)
+                });
+            }
+        }
+    };
+    restrict_file_permissions(settings, &backup_path)?;
+    Ok(Some(backup_path))
+}
+
+/// `<stem>.<timestamp>.bak`, with `.<attempt>` before `.bak` for retries after a
+/// backup of the same name already exists.
+fn backup_file_name(stem: &str, timestamp: &str, attempt: u32) -> String {
+    if attempt == 0 {
+        format!("{}.{}.bak", stem, timestamp)
+    } else {
+        format!("{}.{}.{}.bak", stem, timestamp, attempt)
+    }
+}
+
+fn list_profile_backups(settings: &AppSettings) -> Result<Vec<PathBuf>> {
+    let app_config_dir = get_app_config_dir()?;
+    let stem = Path::new(&settings.profiles_filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("profiles");
+    let prefix = format!("{}.", stem);
+
+    let mut backups: Vec<PathBuf> = fs::read_dir(&app_config_dir)
+        .with_context(|| format!("Failed to read directory {:?}", app_config_dir))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".bak"))
+        })
+        .collect();
+    backups.sort();
+    Ok(backups)
+}
+
 fn read_cli_toml(settings: &AppSettings) -> Result<DocumentMut> {
     let path = get_cli_toml_path(settings)?;
+    verbose!("Reading {} from {:?}", settings.cli_config_filename, path);
     let content = fs::read_to_string(&path).with_context(|| {
         format!(
             "Failed to read {} from {:?}",
             settings.cli_config_filename, path
         )
     })?;
-    content.parse::<DocumentMut>().with_context(|| {
+    let mut doc = content.parse::<DocumentMut>().with_context(|| {
         format!(
             "Failed to parse {} from {:?}",
             settings.cli_config_filename, path
         )
-    })
+    })?;
+    convert_inline_server_configs(&mut doc);
+    Ok(doc)
+}
+
+/// Rewrites `server_configs = [{ ... }, ...]` as `[[server_configs]]` tables so
+/// lookups and updates see its entries. Every key of each entry is kept, and the
+/// rest of the document is untouched. Arrays holding anything but tables are
+/// left as they are.
+fn convert_inline_server_configs(cli_toml: &mut DocumentMut) {
+    let Some(array) = cli_toml
+        .get("server_configs")
+        .and_then(|item| item.as_array())
+    else {
+        return;
+    };
+    if !array.iter().all(|value| value.is_inline_table()) {
+        return;
+    }
+    let mut tables = toml_edit::ArrayOfTables::new();
+    for value in array.iter() {
+        if let Some(inline) = value.as_inline_table() {
+            tables.push(inline.clone().into_table());
+        }
+    }
+    cli_toml["server_configs"] = Item::ArrayOfTables(tables);
+}
+
+/// The `server_configs` tables, created when missing. `None` when the key holds
+/// something that cannot be converted, which is then left alone.
+fn server_configs_mut(cli_toml: &mut DocumentMut) -> Option<&mut toml_edit::ArrayOfTables> {
+    convert_inline_server_configs(cli_toml);
+    cli_toml
+        .entry("server_configs")
+        .or_insert(Item::ArrayOfTables(Default::default()))
+        .as_array_of_tables_mut()
+}
+
+/// Where the previous primary cli.toml is kept for `undo`. Lives in the app config
+/// directory so the SpacetimeDB config directory is left alone.
+fn cli_toml_snapshot_path(settings: &AppSettings) -> Result<PathBuf> {
+    Ok(get_app_config_dir()?.join(format!("{}.prev", settings.cli_config_filename)))
 }
 
 fn write_cli_toml(settings: &AppSettings, doc: &DocumentMut) -> Result<()> {
     let path = get_cli_toml_path(settings)?;
-    fs::write(&path, doc.to_string()).with_context(|| {
+    if path.exists() && !dry_run() {
+        let snapshot_path = cli_toml_snapshot_path(settings)?;
+        verbose!(
+            "Saving previous {} to {:?}",
+            settings.cli_config_filename,
+            snapshot_path
+        );
+        fs::copy(&path, &snapshot_path).with_context(|| {
+            format!(
+                "Failed to save previous {} to {:?}",
+                settings.cli_config_filename, snapshot_path
+            )
+        })?;
+        restrict_file_permissions(settings, &snapshot_path)?;
+    }
+    verbose!("Writing {} to {:?}", settings.cli_config_filename, path);
+    let created = !path.exists();
+    write_file_atomically(&path, &doc.to_string()).with_context(|| {
         format!(
             "Failed to write {} to {:?}",
             settings.cli_config_filename, path
         )
     })?;
-    println!("Successfully updated {}.", settings.cli_config_filename);
+    if created {
+        restrict_file_permissions(settings, &path)?;
+    }
+    record_cli_toml_fingerprint(
+        doc.get(&settings.cli_token_key)
+            .and_then(|item| item.as_str()),
+    )?;
+    if !dry_run() {
+        // Shown in full so a misconfigured `cli_config_dir_from_home` is easy to spot.
+        report_file_updated(&format!(
+            "{} at {}",
+            settings.cli_config_filename,
+            path.display()
+        ));
+    }
+
+    for mirror_path in get_cli_toml_paths(settings)?.iter().skip(1) {
+        mirror_cli_toml(settings, doc, mirror_path)?;
+        if !dry_run() {
+            info!("Mirrored active session to {:?}.", mirror_path);
+        }
+    }
+    Ok(())
+}
+
+/// Copies the keys this tool manages from `doc` into the cli.toml at `path`,
+/// leaving any other keys in that file untouched.
+fn mirror_cli_toml(settings: &AppSettings, doc: &DocumentMut, path: &Path) -> Result<()> {
+    if let Some(parent_dir) = path.parent() {
+        fs::create_dir_all(parent_dir)
+            .with_context(|| format!("Failed to create directory {:?}", parent_dir))?;
+    }
+    let mut mirror = if path.exists() {
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {:?}", path))?
+            .parse::<DocumentMut>()
+            .with_context(|| format!("Failed to parse {:?}", path))?
+    } else {
+        DocumentMut::new()
+    };
+
+    for key in [
+        settings.cli_token_key.as_str(),
+        "default_host",
+        "default_server",
+        "server_configs",
+    ] {
+        match doc.get(key) {
+            Some(item) => mirror[key] = item.clone(),
+            None => {
+                mirror.remove(key);
+            }
+        }
+    }
+    let created = !path.exists();
+    write_file_atomically(path, &mirror.to_string())
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    if created {
+        restrict_file_permissions(settings, path)?;
+    }
     Ok(())
 }
 
@@ -369,716 +1982,4657 @@ fn get_current_environment(settings: &AppSettings) -> Result<Option<String>> {
         .map(|s| s.to_string()))
 }
 
-fn run_external_command(command_name: &str, args: &[&str]) -> Result<()> {
-    println!("Running: {} {}...", command_name, args.join(" "));
-    let mut cmd = StdCommand::new(command_name);
-    cmd.args(args);
+/// Stores the token and server currently in cli.toml as a profile. An existing
+/// profile is only replaced when `overwrite` is set; its note and tags are kept.
+fn save_active_session(
+    settings: &AppSettings,
+    profiles: &mut UserProfiles,
+    profile_name: &str,
+    overwrite: bool,
+) -> Result<()> {
+    let cli_toml_path = get_cli_toml_path(settings)?;
+    if !cli_toml_path.exists() {
+        anyhow::bail!(
+            "{} does not exist. Cannot save token.",
+            settings.cli_config_filename
+        );
+    }
+    let cli_toml = read_cli_toml(settings)?;
 
-    let status = cmd
-        .stdin(std::process::Stdio::inherit())
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .status()
-        .with_context(|| {
-            format!(
-                "Failed to execute command: {}. Is '{}' in your PATH?",
-                command_name, command_name
-            )
-        })?;
+    if profiles.0.contains_key(profile_name) && !overwrite {
+        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+            "Profile '{}' already exists in {}. Use a different name or pass --overwrite.",
+            profile_name, settings.profiles_filename
+        )));
+    }
 
-    if status.success() {
-        println!(
-            "Command '{} {}' executed successfully.",
-            command_name,
-            args.join(" ")
+    let Some(token_item) = cli_toml.get(&settings.cli_token_key) else {
+        anyhow::bail!(
+            "User is not logged in. Token key '{}' not found in {}.",
+            settings.cli_token_key,
+            settings.cli_config_filename
         );
-        Ok(())
-    } else {
+    };
+    let Some(token) = token_item.as_str() else {
+        anyhow::bail!("Token in {} is not a string.", settings.cli_config_filename);
+    };
+    let Some(address) = active_session_address(&cli_toml) else {
         anyhow::bail!(
-            "Command '{} {}' failed with status: {}",
-            command_name,
-            args.join(" "),
-            status
+            "'default_host' not found in {}. Cannot save profile.",
+            settings.cli_config_filename
         );
-    }
+    };
+
+    let profile = profiles
+        .0
+        .entry(profile_name.to_string())
+        .or_insert_with(|| Profile::new(token.to_string(), address.clone()));
+    profile.token = token.to_string();
+    profile.set_address(address);
+    write_profiles(settings, profiles)?;
+    info!(
+        "Saved current active session as profile '{}' in {}.",
+        profile_name, settings.profiles_filename
+    );
+    Ok(())
 }
 
-fn mask_token(token: &str) -> String {
-    if token.len() <= 10 {
-        // Arbitrary length, too short to mask meaningfully
-        return token.to_string();
+/// Address of the session in cli.toml. `default_host` alone loses the protocol
+/// and sub-path, so the `server_configs` entry named by `default_server` is
+/// preferred when it points elsewhere; re-activating the profile then writes
+/// the same target back.
+fn active_session_address(cli_toml: &DocumentMut) -> Option<String> {
+    let default_host = cli_toml.get("default_host").and_then(|item| item.as_str());
+    let server_config = cli_toml
+        .get("default_server")
+        .and_then(|item| item.as_str())
+        .and_then(|nickname| {
+            cli_toml
+                .get("server_configs")?
+                .as_array_of_tables()?
+                .iter()
+                .find(|table| table.get("nickname").and_then(|v| v.as_str()) == Some(nickname))
+        });
+    match (default_host, server_config) {
+        (Some(host), Some(table)) if server_target_matches(table, host) => Some(host.to_string()),
+        (_, Some(table)) => server_config_address(table).or(default_host.map(str::to_string)),
+        (Some(host), None) => Some(host.to_string()),
+        (None, None) => None,
     }
-    format!("{}...{}", &token[..5], &token[token.len() - 5..])
 }
 
-fn normalize_identity_base(address: &str) -> String {
-    let trimmed = address.trim_end_matches('/');
-    trimmed
-        .strip_suffix("/spacetime")
-        .unwrap_or(trimmed)
-        .to_string()
+/// Rebuilds an address such as `https://host:port/path` from a `server_configs` entry.
+/// A missing `protocol` means http, as in `normalize_server_target`.
+fn server_config_address(table: &toml_edit::Table) -> Option<String> {
+    let host = table.get("host").and_then(|v| v.as_str())?;
+    let protocol = table
+        .get("protocol")
+        .and_then(|v| v.as_str())
+        .unwrap_or("http");
+    let path = table.get("path").and_then(|v| v.as_str()).unwrap_or("");
+    Some(format!("{}://{}{}", protocol, host, path))
 }
 
-fn normalize_server_target(address: &str) -> (String, String) {
-    if address == "local" {
-        return ("http".to_string(), "127.0.0.1:3000".to_string());
-    }
-    let trimmed = address.trim_end_matches('/');
-    let trimmed = trimmed
-        .strip_suffix("/spacetime")
-        .unwrap_or(trimmed)
-        .trim_end_matches('/');
-    if let Some(host) = trimmed.strip_prefix("https://") {
-        return ("https".to_string(), host.split('/').next().unwrap_or("").to_string());
-    }
-    if let Some(host) = trimmed.strip_prefix("http://") {
-        return ("http".to_string(), host.split('/').next().unwrap_or("").to_string());
-    }
-    ("http".to_string(), trimmed.split('/').next().unwrap_or("").to_string())
+/// Hex digest of a token, stored instead of the token itself so the app
+/// config dir does not hold another copy of the secret.
+fn token_fingerprint(token: &str) -> String {
+    Blake2s256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
 }
 
-fn update_cli_server_target(cli_toml: &mut DocumentMut, profile_name: &str, address: &str) {
-    let (protocol, host) = normalize_server_target(address);
-    cli_toml["default_server"] = Item::Value(profile_name.into());
+fn cli_toml_fingerprint_path() -> Result<PathBuf> {
+    Ok(get_app_config_dir()?.join(CLI_TOML_FINGERPRINT_FILENAME))
+}
 
-    if cli_toml.get("server_configs").is_none() {
-        cli_toml["server_configs"] = Item::ArrayOfTables(Default::default());
-    }
-    if let Some(array) = cli_toml["server_configs"].as_array_of_tables_mut() {
-        for table in array.iter_mut() {
-            if table.get("nickname").and_then(|v| v.as_str()) == Some(profile_name) {
-                table["host"] = Item::Value(host.clone().into());
-                table["protocol"] = Item::Value(protocol.clone().into());
-                return;
-            }
-        }
-        let mut table = toml_edit::Table::new();
-        table["nickname"] = Item::Value(profile_name.into());
-        table["host"] = Item::Value(host.into());
-        table["protocol"] = Item::Value(protocol.into());
-        array.push(table);
+/// Remembers which token this tool last left in cli.toml (empty if none).
+fn record_cli_toml_fingerprint(token: Option<&str>) -> Result<()> {
+    if dry_run() {
+        return Ok(());
     }
+    let path = cli_toml_fingerprint_path()?;
+    write_file_atomically(&path, &token.map(token_fingerprint).unwrap_or_default())
+        .with_context(|| format!("Failed to write {:?}", path))
 }
 
-fn sync_server_configs_from_profiles(cli_toml: &mut DocumentMut, profiles: &UserProfiles) {
-    if cli_toml.get("server_configs").is_none() {
-        cli_toml["server_configs"] = Item::ArrayOfTables(Default::default());
+/// Warns when the active token in cli.toml is not the one this tool last wrote,
+/// e.g. after a direct `spacetime login`, and offers to save that session as a
+/// profile before the running command overwrites it.
+fn check_external_cli_toml_change(settings: &AppSettings) -> Result<()> {
+    let path = cli_toml_fingerprint_path()?;
+    if !path.exists() {
+        return Ok(());
     }
-    if let Some(array) = cli_toml["server_configs"].as_array_of_tables_mut() {
-        for (name, profile) in profiles.0.iter() {
-            let (protocol, host) = normalize_server_target(&profile.address);
-            let mut updated = false;
-            for table in array.iter_mut() {
-                if table.get("nickname").and_then(|v| v.as_str()) == Some(name.as_str()) {
-                    table["host"] = Item::Value(host.clone().into());
-                    table["protocol"] = Item::Value(protocol.clone().into());
-                    updated = true;
-                    break;
+    let recorded =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    let active_token = read_active_token(settings)?;
+    let live = active_token
+        .as_deref()
+        .map(token_fingerprint)
+        .unwrap_or_default();
+    if recorded.trim() == live {
+        return Ok(());
+    }
+    eprintln!(
+        "Warning: {} was changed outside spacetime-token since it last wrote it (e.g. by `spacetime login`).",
+        settings.cli_config_filename
+    );
+
+    if let Some(token) = &active_token {
+        let mut profiles = read_profiles(settings)?;
+        if !profiles.0.values().any(|profile| &profile.token == token) {
+            if dry_run() || !std::io::stdin().is_terminal() {
+                eprintln!(
+                    "The current session is not saved in any profile. Run `spacetime-token save <NAME>` to keep it before it is overwritten."
+                );
+            } else {
+                let name: String = Input::with_theme(prompt_theme().as_ref())
+                    .with_prompt("Save the current session as profile (empty to skip)")
+                    .allow_empty(true)
+                    .validate_with(|name: &String| -> std::result::Result<(), String> {
+                        if profiles.0.contains_key(name.trim()) {
+                            Err(format!("Profile '{}' already exists.", name.trim()))
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .interact_text()?;
+                let name = name.trim();
+                if !name.is_empty() {
+                    save_active_session(settings, &mut profiles, name, false)?;
                 }
             }
-            if !updated {
-                let mut table = toml_edit::Table::new();
-                table["nickname"] = Item::Value(name.clone().into());
-                table["host"] = Item::Value(host.into());
-                table["protocol"] = Item::Value(protocol.into());
-                array.push(table);
-            }
         }
     }
+    // Acknowledged, so the warning is not repeated on every command.
+    record_cli_toml_fingerprint(active_token.as_deref())
 }
 
-fn fetch_server_issued_token(address: &str) -> Result<String> {
-    let base = normalize_identity_base(address);
-    let url = format!("{}/v1/identity", base);
-    let client = BlockingHttpClient::builder()
-        .timeout(std::time::Duration::from_secs(10))
-        .build()
-        .context("Failed to build HTTP client")?;
-    let response = client
-        .post(&url)
-        .header(CONTENT_LENGTH, "0")
-        .send()
-        .with_context(|| format!("Failed to call {}", url))?;
-    if !response.status().is_success() {
-        anyhow::bail!(
-            "Server-issued login failed with status {} for {}",
-            response.status(),
-            url
+fn read_active_token(settings: &AppSettings) -> Result<Option<String>> {
+    let cli_toml_path = get_cli_toml_path(settings)?;
+    if !cli_toml_path.exists() {
+        return Ok(None);
+    }
+    let cli_toml = read_cli_toml(settings)?;
+    Ok(cli_toml
+        .get(&settings.cli_token_key)
+        .and_then(|item| item.as_str())
+        .map(|s| s.to_string()))
+}
+
+/// Writes a stored profile's token and server target into cli.toml and
+/// records the activation time in the profiles file.
+fn activate_profile(
+    settings: &AppSettings,
+    profiles: &mut UserProfiles,
+    profile_name: &str,
+    command: &str,
+) -> Result<()> {
+    activate_profile_at(settings, profiles, profile_name, None, command)
+}
+
+/// Like `activate_profile`, but optionally points cli.toml at `address_override`
+/// instead of the profile's stored address.
+fn activate_profile_at(
+    settings: &AppSettings,
+    profiles: &mut UserProfiles,
+    profile_name: &str,
+    address_override: Option<&str>,
+    command: &str,
+) -> Result<()> {
+    let mut history = read_profile_history().unwrap_or_default();
+    let previously_active = active_profile_name(settings, profiles, &history);
+    let profile = profiles.0.get_mut(profile_name).ok_or_else(|| {
+        ErrorCategory::NotFound.error(format!("Profile '{}' not found.", profile_name))
+    })?;
+    ensure_token_resolved(profile_name, profile)?;
+    profile.last_used = Some(now_rfc3339());
+    write_profiles(settings, profiles)?;
+
+    let address = address_override.unwrap_or(&profiles.0[profile_name].address);
+    let mut cli_toml = load_or_init_cli_toml(settings)?;
+    apply_profile_to_cli_toml(settings, &mut cli_toml, profiles, profile_name, address);
+    write_cli_toml(settings, &cli_toml)?;
+
+    // The switch already happened, so a log that cannot be written only warrants a warning.
+    if let Err(e) = append_audit_log(settings, command, profile_name, address) {
+        eprintln!("Warning: failed to write {}: {:#}", AUDIT_LOG_FILENAME, e);
+    }
+    if let Some(previous) = previously_active.filter(|name| name != profile_name) {
+        history.previous = Some(previous);
+    }
+    history.current = Some(profile_name.to_string());
+    if let Err(e) = write_profile_history(&history) {
+        eprintln!(
+            "Warning: failed to write {}: {:#}",
+            PROFILE_HISTORY_FILENAME, e
         );
     }
-    let identity = response
-        .json::<IdentityResponse>()
-        .context("Failed to parse identity response")?;
-    if identity.token.trim().is_empty() {
-        anyhow::bail!("Identity response did not include a token.");
+    Ok(())
+}
+
+/// The last two profiles activated by this tool, for `switch --previous`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ProfileHistory {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<String>,
+}
+
+fn read_profile_history() -> Result<ProfileHistory> {
+    let path = get_app_config_dir()?.join(PROFILE_HISTORY_FILENAME);
+    if !path.exists() {
+        return Ok(ProfileHistory::default());
     }
-    Ok(identity.token)
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse {:?}", path))
 }
 
-fn main() -> Result<()> {
-    let settings = load_app_settings().context("Failed to load application settings")?;
-    let cli = Cli::parse();
+fn write_profile_history(history: &ProfileHistory) -> Result<()> {
+    if dry_run() {
+        return Ok(());
+    }
+    let path = get_app_config_dir()?.join(PROFILE_HISTORY_FILENAME);
+    let content = toml::to_string_pretty(history).context("Failed to serialize history")?;
+    write_file_atomically(&path, &content).with_context(|| format!("Failed to write {:?}", path))
+}
 
-    match cli.command {
-        Commands::Set(args) => {
-            let mut profiles = read_profiles(&settings)?;
-            let address = args.address.unwrap_or_else(|| {
-                get_current_environment(&settings)
-                    .unwrap_or_default()
-                    .unwrap_or_else(|| "local".to_string())
-            });
-            let profile = Profile {
-                token: args.token.clone(),
-                address,
-            };
-            profiles
+/// Name of the profile holding the token that is active in cli.toml. When
+/// several profiles share it, the one last activated by this tool wins.
+fn active_profile_name(
+    settings: &AppSettings,
+    profiles: &UserProfiles,
+    history: &ProfileHistory,
+) -> Option<String> {
+    let token = read_active_token(settings).ok().flatten()?;
+    if let Some(current) = &history.current {
+        if profiles.0.get(current).is_some_and(|p| p.token == token) {
+            return Some(current.clone());
+        }
+    }
+    let mut names: Vec<&String> = profiles
+        .0
+        .iter()
+        .filter(|(_, profile)| profile.token == token)
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    names.first().map(|name| name.to_string())
+}
+
+/// Appends one line per activation to audit.log in the app config directory.
+/// Only the time, command, profile name and address are recorded, never the token.
+fn append_audit_log(
+    settings: &AppSettings,
+    command: &str,
+    profile_name: &str,
+    address: &str,
+) -> Result<()> {
+    if !settings.audit_log || dry_run() {
+        return Ok(());
+    }
+    let path = get_app_config_dir()?.join(AUDIT_LOG_FILENAME);
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    writeln!(
+        file,
+        "{} {} profile={} address={}",
+        now_rfc3339(),
+        command,
+        profile_name,
+        address
+    )
+    .with_context(|| format!("Failed to append to {:?}", path))
+}
+
+/// Saves `token` and `address` under `profile_name`, creating the profile if
+/// needed, and makes it the active profile.
+fn store_and_activate_profile(
+    settings: &AppSettings,
+    profiles: &mut UserProfiles,
+    profile_name: &str,
+    token: String,
+    address: String,
+    command: &str,
+) -> Result<()> {
+    store_profile(profiles, profile_name, token, address);
+    activate_profile(settings, profiles, profile_name, command)
+}
+
+/// Adds or updates `profile_name` in `profiles` without writing anything.
+fn store_profile(profiles: &mut UserProfiles, profile_name: &str, token: String, address: String) {
+    let profile = profiles
+        .0
+        .entry(profile_name.to_string())
+        .or_insert_with(|| Profile::new(token.clone(), address.clone()));
+    profile.token = token;
+    profile.set_address(address);
+}
+
+/// Every address associated with `token`, across all profiles that store it.
+fn token_addresses(profiles: &UserProfiles, token: &str) -> Vec<String> {
+    let mut addresses: Vec<String> = profiles
+        .0
+        .values()
+        .filter(|profile| profile.token == token)
+        .flat_map(|profile| {
+            std::iter::once(profile.address.clone()).chain(profile.previous_addresses.clone())
+        })
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// Picks a profile whose name fuzzy-matches `query`, prompting only when several match.
+fn select_profile_fuzzy(profile_names: &[String], query: &str) -> Result<String> {
+    if let Some(exact) = profile_names
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(query))
+    {
+        return Ok(exact.clone());
+    }
+
+    let matcher = SkimMatcherV2::default();
+    let mut scored: Vec<(i64, &String)> = profile_names
+        .iter()
+        .filter_map(|name| matcher.fuzzy_match(name, query).map(|score| (score, name)))
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+
+    match scored.len() {
+        0 => {
+            anyhow::bail!(ErrorCategory::NotFound.error(format!("No profiles match '{}'.", query)))
+        }
+        1 => Ok(scored[0].1.clone()),
+        _ => {
+            let candidates: Vec<&String> = scored.iter().map(|(_, name)| *name).collect();
+            let selection = FuzzySelect::with_theme(prompt_theme().as_ref())
+                .with_prompt(format!("Several profiles match '{}'", query))
+                .items(&candidates)
+                .with_initial_text(query)
+                .default(0)
+                .interact_opt()?
+                .context("No profile selected or selection cancelled.")?;
+            Ok(candidates[selection].clone())
+        }
+    }
+}
+
+/// Prints profile names for shell completion without creating or migrating any files,
+/// so a missing or unreadable profiles file simply yields no output.
+fn print_profile_names_for_completion() {
+    let Ok(config_dir) = resolve_app_config_dir() else {
+        return;
+    };
+    let settings = fs::read_to_string(config_dir.join(DEFAULT_CONFIG_FILENAME))
+        .ok()
+        .and_then(|content| toml::from_str::<AppSettings>(&content).ok())
+        .unwrap_or_default();
+    let Some(profiles) = fs::read_to_string(config_dir.join(&settings.profiles_filename))
+        .ok()
+        .and_then(|content| toml::from_str::<UserProfiles>(&content).ok())
+    else {
+        return;
+    };
+
+    let mut profile_names: Vec<&String> = profiles.0.keys().collect();
+    profile_names.sort();
+    for name in profile_names {
+        println!("{}", name);
+    }
+}
+
+/// Conventional per-user location of a completion script: bash-completion's
+/// user directory, `~/.zfunc` for zsh, and fish's `completions` directory.
+fn completion_install_path(shell: clap_complete::Shell, bin_name: &str) -> Result<PathBuf> {
+    let home_dir = dirs::home_dir().context("Failed to get home directory")?;
+    let xdg_dir = |var: &str, fallback: &str| {
+        std::env::var_os(var)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home_dir.join(fallback))
+    };
+    match shell {
+        clap_complete::Shell::Bash => Ok(xdg_dir("XDG_DATA_HOME", ".local/share")
+            .join("bash-completion/completions")
+            .join(bin_name)),
+        clap_complete::Shell::Zsh => Ok(home_dir.join(".zfunc").join(format!("_{}", bin_name))),
+        clap_complete::Shell::Fish => Ok(xdg_dir("XDG_CONFIG_HOME", ".config")
+            .join("fish/completions")
+            .join(format!("{}.fish", bin_name))),
+        other => anyhow::bail!(ErrorCategory::Invalid.error(format!(
+            "--install is not supported for {}. Redirect the printed script instead.",
+            other
+        ))),
+    }
+}
+
+/// Shell code appended to the generated completion script so that profile-name
+/// arguments are completed by calling the hidden `__complete_profiles` command.
+fn profile_completion_snippet(shell: clap_complete::Shell, bin_name: &str) -> Option<String> {
+    let subcommands = PROFILE_NAME_SUBCOMMANDS.join("|");
+    match shell {
+        clap_complete::Shell::Bash => Some(format!(
+            r#"
+_{bin_name}_with_profiles() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [[ ${{COMP_CWORD}} -eq 2 && "${{cur}}" != -* ]]; then
+        case "${{COMP_WORDS[1]}}" in
+            {subcommands})
+                COMPREPLY=( $(compgen -W "$({bin_name} __complete_profiles 2>/dev/null)" -- "${{cur}}") )
+                return 0
+                ;;
+        esac
+    fi
+    _{bin_name} "$@"
+}}
+complete -F _{bin_name}_with_profiles -o nosort -o bashdefault -o default {bin_name}
+"#
+        )),
+        clap_complete::Shell::Zsh => Some(format!(
+            r#"
+_{bin_name}_with_profiles() {{
+    if (( CURRENT == 3 )) && [[ ${{words[2]}} == ({subcommands}) && ${{words[CURRENT]}} != -* ]]; then
+        local -a profiles
+        profiles=(${{(f)"$({bin_name} __complete_profiles 2>/dev/null)"}})
+        _describe 'profile' profiles && return
+    fi
+    _{bin_name} "$@"
+}}
+compdef _{bin_name}_with_profiles {bin_name}
+"#
+        )),
+        clap_complete::Shell::Fish => Some(format!(
+            "complete -c {bin_name} -n \"__fish_seen_subcommand_from {}\" -f -a \"({bin_name} __complete_profiles 2>/dev/null)\"\n",
+            PROFILE_NAME_SUBCOMMANDS.join(" ")
+        )),
+        _ => None,
+    }
+}
+
+/// Widths of the name and `(address: ...)` columns that align `profiles` in
+/// `format_profile_line`.
+fn profile_line_widths<'a>(
+    profiles: impl IntoIterator<Item = (&'a String, &'a Profile)>,
+) -> (usize, usize) {
+    profiles
+        .into_iter()
+        .fold((0, 0), |(name_width, address_width), (name, profile)| {
+            (
+                name_width.max(name.chars().count()),
+                address_width.max(format!("(address: {})", profile.address).chars().count()),
+            )
+        })
+}
+
+/// Formats a profile as a `list` line, e.g. `- dev (address: local) (note) (current)`,
+/// with the name and address padded to `widths`. Unless colors are disabled, the
+/// `(current)` marker is highlighted and profiles with an expired token are dimmed.
+fn format_profile_line(
+    profile_name: &str,
+    profile: &Profile,
+    active_token: Option<&str>,
+    widths: (usize, usize),
+) -> String {
+    let (name_width, address_width) = widths;
+    let mut line = format!(
+        "- {:<name_width$} {:<address_width$}",
+        profile_name,
+        format!("(address: {})", profile.address),
+    );
+    if !profile.tags.is_empty() {
+        line.push_str(&format!(" (tags: {})", profile.tags.join(", ")));
+    }
+    if let Some(note) = &profile.note {
+        line.push_str(&format!(" ({})", note));
+    }
+    let line = line.trim_end();
+    let expired = token_expiry(&profile.token).is_some_and(|exp| exp <= chrono::Utc::now());
+    let mut line = if expired {
+        style(line).dim().to_string()
+    } else {
+        line.to_string()
+    };
+    if active_token == Some(profile.token.as_str()) {
+        line.push_str(&format!(" {}", style("(current)").green().bold()));
+    }
+    line
+}
+
+fn profile_json(
+    profile_name: &str,
+    profile: &Profile,
+    active_token: Option<&str>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "name": profile_name,
+        "address": profile.address,
+        "current": active_token == Some(profile.token.as_str()),
+        "created_at": profile.created_at,
+        "last_used": profile.last_used,
+        "note": profile.note,
+        "tags": profile.tags,
+    })
+}
+
+/// A token's expiry for display: RFC 3339, marked `(expired)` once past, or
+/// `unknown` when the token has no decodable `exp` claim.
+fn expiry_label(token: &str) -> String {
+    match token_expiry(token) {
+        Some(exp) if exp <= chrono::Utc::now() => format!(
+            "{} (expired)",
+            exp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        ),
+        Some(exp) => exp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        None => "unknown".to_string(),
+    }
+}
+
+const PROFILE_TABLE_HEADERS: &[&str] = &["NAME", "ADDRESS", "EXPIRY", "CURRENT"];
+
+/// One row of the `--output table` view of profiles, under `PROFILE_TABLE_HEADERS`.
+fn profile_table_row(
+    profile_name: &str,
+    profile: &Profile,
+    active_token: Option<&str>,
+) -> Vec<String> {
+    let current = active_token == Some(profile.token.as_str());
+    vec![
+        profile_name.to_string(),
+        profile.address.clone(),
+        expiry_label(&profile.token),
+        if current { "*" } else { "" }.to_string(),
+    ]
+}
+
+/// Renders rows under a header line with each column padded to its widest cell.
+fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let render_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        line.join("  ").trim_end().to_string()
+    };
+    let mut lines = vec![render_row(headers.to_vec())];
+    lines.extend(
+        rows.iter()
+            .map(|row| render_row(row.iter().map(String::as_str).collect())),
+    );
+    lines.join("\n")
+}
+
+/// Returns profile names ordered for display. `LastUsed` puts the most recently
+/// used first and profiles that were never activated last.
+fn sort_profile_names(profiles: &HashMap<String, Profile>, sort: ListSort) -> Vec<String> {
+    let mut names: Vec<String> = profiles.keys().cloned().collect();
+    names.sort();
+    match sort {
+        ListSort::Name => {}
+        ListSort::Address => names.sort_by(|a, b| profiles[a].address.cmp(&profiles[b].address)),
+        // `None` sorts below any timestamp, so reversing puts never-used profiles last.
+        ListSort::LastUsed => {
+            names.sort_by_key(|name| std::cmp::Reverse(profiles[name].last_used.clone()))
+        }
+    }
+    names
+}
+
+/// Summary line printed after `list`, e.g. `3 profiles (2 in environment 'local')`.
+/// `environment` is the current environment; the filters in `args` are spelled out.
+fn list_summary(
+    profiles: &HashMap<String, Profile>,
+    args: &ListArgs,
+    environment: Option<&str>,
+) -> String {
+    let count = profiles.len();
+    let mut summary = format!("{} profile{}", count, if count == 1 { "" } else { "s" });
+    if args.env {
+        if let Some(env) = environment {
+            summary.push_str(&format!(" in environment '{}'", env));
+        }
+    } else if let Some(address) = &args.address {
+        summary.push_str(&format!(" with address '{}'", address));
+    }
+    if let Some(tag) = &args.tag {
+        summary.push_str(&format!(" tagged '{}'", tag));
+    }
+    if args.stale {
+        summary.push_str(if args.include_unknown {
+            " with expired or undated tokens"
+        } else {
+            " with expired tokens"
+        });
+    }
+    if !args.env {
+        if let Some(env) = environment {
+            let in_env = profiles.values().filter(|p| p.address == env).count();
+            summary.push_str(&format!(" ({} in environment '{}')", in_env, env));
+        }
+    }
+    summary
+}
+
+/// Snapshot of a cli.toml file that is written back when dropped, so temporary
+/// changes are undone even if the work in between fails.
+struct CliTomlRestoreGuard {
+    path: PathBuf,
+    original: Option<String>,
+}
+
+impl CliTomlRestoreGuard {
+    fn snapshot(path: PathBuf) -> Result<Self> {
+        let original = if path.exists() {
+            Some(fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?)
+        } else {
+            None
+        };
+        Ok(Self { path, original })
+    }
+}
+
+impl Drop for CliTomlRestoreGuard {
+    fn drop(&mut self) {
+        let result = match &self.original {
+            Some(content) => write_file_atomically(&self.path, content),
+            None => fs::remove_file(&self.path).map_err(anyhow::Error::from),
+        };
+        match result {
+            Ok(()) => info!("Restored {:?}.", self.path),
+            Err(e) => eprintln!("Warning: failed to restore {:?}: {:#}", self.path, e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+struct DoctorFinding {
+    severity: Severity,
+    message: String,
+}
+
+impl DoctorFinding {
+    fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+/// Checks the invariants between the stored profiles and cli.toml.
+fn diagnose(
+    settings: &AppSettings,
+    profiles: &UserProfiles,
+    cli_toml: Option<&DocumentMut>,
+) -> Vec<DoctorFinding> {
+    let mut findings = Vec::new();
+    let mut names: Vec<&String> = profiles.0.keys().collect();
+    names.sort();
+    for name in names {
+        let address = &profiles.0[name].address;
+        if let Some((normalized, _)) = normalized_address(address) {
+            findings.push(DoctorFinding::warning(format!(
+                "Profile '{}' address '{}' is not normalized; --fix rewrites it as '{}'.",
+                name, address, normalized
+            )));
+        }
+    }
+    let Some(cli_toml) = cli_toml else {
+        findings.push(DoctorFinding::warning(format!(
+            "{} not found. No active session is set.",
+            settings.cli_config_filename
+        )));
+        return findings;
+    };
+
+    let server_configs: Vec<&toml_edit::Table> = cli_toml
+        .get("server_configs")
+        .and_then(|item| item.as_array_of_tables())
+        .map(|array| array.iter().collect())
+        .unwrap_or_default();
+    let find_server_config = |nickname: &str| {
+        server_configs
+            .iter()
+            .find(|table| table.get("nickname").and_then(|v| v.as_str()) == Some(nickname))
+    };
+
+    if let Some(default_server) = cli_toml.get("default_server").and_then(|v| v.as_str()) {
+        if find_server_config(default_server).is_none() {
+            findings.push(DoctorFinding::error(format!(
+                "default_server '{}' has no matching server_configs entry.",
+                default_server
+            )));
+        }
+    }
+
+    match cli_toml
+        .get(&settings.cli_token_key)
+        .and_then(|item| item.as_str())
+    {
+        Some(active_token) => {
+            if !profiles
                 .0
-                .insert(args.profile_name.clone(), profile.clone());
-            write_profiles(&settings, &profiles)?;
-            println!(
-                "Profile '{}' saved/updated in {}.",
-                args.profile_name, settings.profiles_filename
-            );
+                .values()
+                .any(|profile| profile.token == active_token)
+            {
+                findings.push(DoctorFinding::warning(format!(
+                    "The active token in {} does not match any profile.",
+                    settings.cli_config_filename
+                )));
+            }
+            if let Err(e) = decode_jwt_claims(active_token) {
+                findings.push(DoctorFinding::warning(format!(
+                    "The active token does not parse as a JWT ({:#}).",
+                    e
+                )));
+            }
+        }
+        None => findings.push(DoctorFinding::warning(format!(
+            "No active token (key '{}') in {}.",
+            settings.cli_token_key, settings.cli_config_filename
+        ))),
+    }
 
-            let mut cli_toml = load_or_init_cli_toml(&settings)?;
-            cli_toml[&settings.cli_token_key] = Item::Value(args.token.into());
-            cli_toml["default_host"] = Item::Value(profile.address.into());
-            update_cli_server_target(
-                &mut cli_toml,
-                &args.profile_name,
-                &profiles.0[&args.profile_name].address,
+    let mut profile_names: Vec<&String> = profiles.0.keys().collect();
+    profile_names.sort();
+    for name in profile_names {
+        let profile = &profiles.0[name];
+        match find_server_config(name) {
+            None => findings.push(DoctorFinding::warning(format!(
+                "Profile '{}' has no server_configs entry.",
+                name
+            ))),
+            Some(table) => {
+                // A `switch --address` override may point at another known address
+                let is_known_override = token_addresses(profiles, &profile.token)
+                    .iter()
+                    .any(|address| server_target_matches(table, address));
+                if !is_known_override {
+                    let (protocol, host) = normalize_server_target(&profile.address);
+                    let path = normalize_server_path(&profile.address).unwrap_or_default();
+                    let stored = |key: &str| table.get(key).and_then(|v| v.as_str()).unwrap_or("");
+                    findings.push(DoctorFinding::error(format!(
+                        "Profile '{}' address '{}' resolves to {}://{}{}, but server_configs has {}://{}{}.",
+                        name,
+                        profile.address,
+                        protocol,
+                        host,
+                        path,
+                        stored("protocol"),
+                        stored("host"),
+                        stored("path")
+                    )));
+                }
+            }
+        }
+    }
+    findings
+}
+
+fn print_findings(findings: &[DoctorFinding]) {
+    if findings.is_empty() {
+        println!("No problems found.");
+        return;
+    }
+    for finding in findings {
+        let label = match finding.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        println!("[{}] {}", label, finding.message);
+    }
+}
+
+/// Parses a `KEY=VALUE` argument for `exec --env`.
+fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", value)),
+    }
+}
+
+fn run_external_command(
+    command_name: &str,
+    args: &[&str],
+    envs: &[(String, String)],
+) -> Result<()> {
+    match external_command_status(command_name, args, envs)? {
+        Some(status) if !status.success() => anyhow::bail!(
+            "Command '{} {}' failed with status: {}",
+            command_name,
+            args.join(" "),
+            status
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Runs an external command with inherited stdio and returns how it exited, or
+/// `None` under `--dry-run`. Signals that would kill this process first are
+/// handled meanwhile, so callers can still restore cli.toml afterwards.
+fn external_command_status(
+    command_name: &str,
+    args: &[&str],
+    envs: &[(String, String)],
+) -> Result<Option<std::process::ExitStatus>> {
+    // Only the names are shown; values may be secrets.
+    let env_note = if envs.is_empty() {
+        String::new()
+    } else {
+        let names: Vec<&str> = envs.iter().map(|(key, _)| key.as_str()).collect();
+        format!(" (with {} set)", names.join(", "))
+    };
+    if dry_run() {
+        println!(
+            "(dry run) Would run: {} {}{}",
+            command_name,
+            args.join(" "),
+            env_note
+        );
+        return Ok(None);
+    }
+    info!(
+        "Running: {} {}{}...",
+        command_name,
+        args.join(" "),
+        env_note
+    );
+    let mut cmd = StdCommand::new(command_name);
+    cmd.args(args);
+    cmd.envs(envs.iter().map(|(key, value)| (key, value)));
+
+    let mut child = cmd
+        .stdin(std::process::Stdio::inherit())
+        .stdout(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .with_context(|| {
+            format!(
+                "Failed to execute command: {}. Is '{}' in your PATH?",
+                command_name, command_name
+            )
+        })?;
+    #[cfg(unix)]
+    forward_signals_to_child(Some(child.id()));
+    let status = child.wait();
+    #[cfg(unix)]
+    forward_signals_to_child(None);
+    let status = status.with_context(|| format!("Failed to wait for {}", command_name))?;
+
+    if status.success() {
+        info!(
+            "Command '{} {}' executed successfully.",
+            command_name,
+            args.join(" ")
+        );
+    }
+    Ok(Some(status))
+}
+
+/// Process the signal handlers pass SIGTERM and SIGHUP on to; 0 when none is running.
+#[cfg(unix)]
+static SIGNAL_FORWARD_PID: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = SIGNAL_FORWARD_PID.load(std::sync::atomic::Ordering::SeqCst);
+    // Ctrl-C already reaches the child through the terminal's process group.
+    if pid > 0 && signal != libc::SIGINT {
+        // SAFETY: kill is async-signal-safe.
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+/// While `child_pid` runs, keeps SIGINT, SIGTERM and SIGHUP from killing this
+/// process, passing the latter two on to the child. `None` restores the defaults.
+#[cfg(unix)]
+fn forward_signals_to_child(child_pid: Option<u32>) {
+    let pid = child_pid
+        .and_then(|pid| i32::try_from(pid).ok())
+        .unwrap_or(0);
+    SIGNAL_FORWARD_PID.store(pid, std::sync::atomic::Ordering::SeqCst);
+    let handler = match child_pid {
+        Some(_) => forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        None => libc::SIG_DFL,
+    };
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: the handler only reads an atomic and calls kill.
+        unsafe {
+            libc::signal(signal, handler);
+        }
+    }
+}
+
+/// Exit code to pass on for a finished child: its own code, or 128 + the signal
+/// that terminated it, as shells report it.
+fn child_exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+fn mask_token(token: &str, reveal: usize) -> String {
+    let chars: Vec<char> = token.chars().collect();
+    if chars.len() <= reveal * 2 {
+        // Too short to show both ends without giving most of it away
+        return "***".to_string();
+    }
+    let head: String = chars[..reveal].iter().collect();
+    let tail: String = chars[chars.len() - reveal..].iter().collect();
+    format!("{}...{}", head, tail)
+}
+
+/// Decodes the claims of a JWT without verifying its signature.
+fn decode_jwt_claims(token: &str) -> Result<serde_json::Value> {
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() != 3 {
+        anyhow::bail!("expected 3 dot-separated segments, found {}", segments.len());
+    }
+    let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let header = engine
+        .decode(segments[0].trim_end_matches('='))
+        .context("header is not valid base64url")?;
+    serde_json::from_slice::<serde_json::Value>(&header).context("header is not valid JSON")?;
+    let payload = engine
+        .decode(segments[1].trim_end_matches('='))
+        .context("payload is not valid base64url")?;
+    serde_json::from_slice(&payload).context("payload is not valid JSON")
+}
+
+/// Cheap check for common paste errors: an empty token, stray whitespace, or
+/// something that is not a three-segment JWT.
+fn looks_like_token(token: &str) -> bool {
+    !token.is_empty() && !token.chars().any(char::is_whitespace) && decode_jwt_claims(token).is_ok()
+}
+
+/// Expiry (`exp` claim) of a JWT, if the token decodes and carries one.
+fn token_expiry(token: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let claims = decode_jwt_claims(token).ok()?;
+    let exp = claims.get("exp")?.as_i64()?;
+    chrono::DateTime::from_timestamp(exp, 0)
+}
+
+/// Port of the `local` shortcut: 3000 for `local`, `N` for `local:N`. `None`
+/// for any other address, including `local:` with an invalid port.
+fn local_port(address: &str) -> Option<u16> {
+    if address == "local" {
+        return Some(3000);
+    }
+    address
+        .strip_prefix("local:")?
+        .parse::<u16>()
+        .ok()
+        .filter(|port| *port != 0)
+}
+
+/// The HTTP base URL for API calls to `address`: websocket schemes become their
+/// HTTP counterparts and bare `host[:port]` values get `http://`.
+fn normalize_identity_base(address: &str) -> String {
+    let (protocol, host) = normalize_server_target(address);
+    if host.is_empty() {
+        let trimmed = address.trim_end_matches('/');
+        return trimmed
+            .strip_suffix("/spacetime")
+            .unwrap_or(trimmed)
+            .to_string();
+    }
+    let path = normalize_server_path(address).unwrap_or_default();
+    format!("{}://{}{}", protocol, host, path)
+}
+
+fn normalize_server_target(address: &str) -> (String, String) {
+    if let Some(port) = local_port(address) {
+        return ("http".to_string(), format!("127.0.0.1:{}", port));
+    }
+    let trimmed = address.trim();
+    // Bare `host:port` inputs would otherwise parse with the host as the scheme.
+    let with_scheme = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("http://{}", trimmed)
+    };
+    let Ok(url) = Url::parse(&with_scheme) else {
+        return (
+            "http".to_string(),
+            trimmed.split('/').next().unwrap_or("").to_string(),
+        );
+    };
+
+    // Websocket schemes map onto the HTTP protocol they upgrade from.
+    let protocol = match url.scheme() {
+        "https" | "wss" => "https",
+        _ => "http",
+    };
+    // `host_str` keeps the brackets around IPv6 literals.
+    let host = url.host_str().unwrap_or("");
+    // `Url` drops a port equal to the scheme's default; keep it when it was written.
+    let host = match url.port().or_else(|| written_port(&with_scheme)) {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    (protocol.to_string(), host)
+}
+
+/// The port spelled out in the authority of a `scheme://` address, if any.
+fn written_port(address: &str) -> Option<u16> {
+    let rest = address.split_once("://")?.1;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host_port = authority.rsplit('@').next()?;
+    // Skip past an IPv6 literal, whose colons are not port separators.
+    let after_host = match host_port.rfind(']') {
+        Some(end) => &host_port[end + 1..],
+        None => host_port,
+    };
+    after_host.rsplit_once(':')?.1.parse().ok()
+}
+
+/// Prefixes a bare `host[:port]` address with the `--protocol` scheme so the
+/// choice is kept in profiles.toml and server_configs. Without one, bare hosts
+/// keep defaulting to http, with a warning.
+fn apply_address_protocol(address: &str, protocol: Option<AddressProtocol>) -> String {
+    if local_port(address).is_some() || address.contains("://") {
+        if protocol.is_some() {
+            eprintln!(
+                "Warning: --protocol is ignored because '{}' already sets its protocol.",
+                address
             );
-            sync_server_configs_from_profiles(&mut cli_toml, &profiles);
-            write_cli_toml(&settings, &cli_toml)?;
-            println!(
-                "Profile '{}' also set as active in {}.",
-                args.profile_name, settings.cli_config_filename
+        }
+        return address.to_string();
+    }
+    match protocol {
+        Some(AddressProtocol::Http) => format!("http://{}", address),
+        Some(AddressProtocol::Https) => format!("https://{}", address),
+        None => {
+            eprintln!(
+                "Warning: '{}' has no scheme, so server_configs will use http. Pass --protocol https (or an https:// address) if the server uses HTTPS.",
+                address
             );
+            address.to_string()
         }
-        Commands::Switch(args) => {
+    }
+}
+
+/// Expands an alias from `address_aliases` to its address; other values are returned unchanged.
+fn resolve_address_alias(settings: &AppSettings, address: &str) -> String {
+    match settings.address_aliases.get(address) {
+        Some(resolved) => {
+            verbose!("Address alias '{}' resolves to '{}'.", address, resolved);
+            resolved.clone()
+        }
+        None => address.to_string(),
+    }
+}
+
+/// Alias names are plain words so they cannot be mistaken for a host or URL.
+fn validate_alias_name(name: &str) -> Result<()> {
+    if local_port(name).is_some() || name.starts_with("local:") {
+        anyhow::bail!(
+            ErrorCategory::Invalid.error(format!("'{}' is reserved for the local server.", name))
+        );
+    }
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+            "Invalid alias '{}'. Use letters, digits, '-' and '_' only.",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects malformed server addresses before they are stored. Accepts the
+/// `local`/`local:<port>` shortcut, `http(s)`/`ws(s)` URLs and bare `host[:port]` values.
+fn validate_address(address: &str) -> Result<()> {
+    if local_port(address).is_some() {
+        return Ok(());
+    }
+    if address.starts_with("local:") {
+        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+            "Invalid port in '{}'. Use local:<PORT> with a port from 1 to 65535.",
+            address
+        )));
+    }
+    if address.trim().is_empty() {
+        anyhow::bail!(ErrorCategory::Invalid.error("Address must not be empty."));
+    }
+    if address.trim() != address {
+        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+            "Address '{}' has leading or trailing whitespace.",
+            address
+        )));
+    }
+    if address.chars().any(char::is_whitespace) {
+        anyhow::bail!(
+            ErrorCategory::Invalid.error(format!("Address '{}' contains whitespace.", address))
+        );
+    }
+    let url = match address.split_once("://") {
+        Some((scheme, _)) => {
+            if !matches!(scheme, "http" | "https" | "ws" | "wss") {
+                anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                    "Unsupported scheme '{}' in address '{}'. Expected http, https, ws or wss.",
+                    scheme, address
+                )));
+            }
+            Url::parse(address)
+        }
+        None => Url::parse(&format!("http://{}", address)),
+    }
+    .with_context(|| format!("Invalid address '{}'", address))?;
+    if url.host_str().is_none_or(str::is_empty) {
+        anyhow::bail!(
+            ErrorCategory::Invalid.error(format!("Address '{}' is missing a host.", address))
+        );
+    }
+    Ok(())
+}
+
+/// Sub-path of a deployment behind a gateway, e.g. `/api` for
+/// `https://gateway.example.com/api/spacetime`. `None` when served from the root.
+fn normalize_server_path(address: &str) -> Option<String> {
+    if local_port(address).is_some() {
+        return None;
+    }
+    let trimmed = address.trim();
+    let url = if trimmed.contains("://") {
+        Url::parse(trimmed)
+    } else {
+        Url::parse(&format!("http://{}", trimmed))
+    }
+    .ok()?;
+    let path = url.path().trim_end_matches('/');
+    let path = path.strip_suffix("/spacetime").unwrap_or(path);
+    let path = path.trim_end_matches('/');
+    (!path.is_empty()).then(|| path.to_string())
+}
+
+/// The normalized form of a stored address when it differs, and whether the
+/// change needs confirmation. Surrounding whitespace, trailing slashes and the
+/// case of the scheme and host are fixed outright; replacing an unknown scheme
+/// (e.g. a typo) with http, or changing the port or dropping a query string or
+/// fragment, is not certain. Bare `host[:port]` values and the
+/// `local` shortcut are only trimmed.
+fn normalized_address(address: &str) -> Option<(String, bool)> {
+    let trimmed = address.trim().trim_end_matches('/');
+    if trimmed.is_empty() {
+        return None;
+    }
+    let (normalized, uncertain) = if local_port(trimmed).is_some() || !trimmed.contains("://") {
+        (trimmed.to_string(), false)
+    } else {
+        let url = Url::parse(trimmed).ok()?;
+        let (protocol, host) = normalize_server_target(trimmed);
+        if host.is_empty() {
+            return None;
+        }
+        let known_scheme = matches!(url.scheme(), "http" | "https" | "ws" | "wss");
+        let scheme = if known_scheme {
+            url.scheme()
+        } else {
+            &protocol
+        };
+        let path = url.path().trim_end_matches('/');
+        let normalized = format!("{}://{}{}", scheme, host, path);
+        // The port and any query or fragment must survive untouched; otherwise
+        // the rewrite is a guess.
+        let kept_port = written_port(trimmed) == written_port(&normalized);
+        let kept_rest = url.query().is_none() && url.fragment().is_none();
+        let uncertain = !known_scheme || !kept_port || !kept_rest;
+        (normalized, uncertain)
+    };
+    (normalized != address).then_some((normalized, uncertain))
+}
+
+/// Whether a `server_configs` entry already targets `address`.
+fn server_target_matches(table: &toml_edit::Table, address: &str) -> bool {
+    let (protocol, host) = normalize_server_target(address);
+    let path = normalize_server_path(address);
+    table.get("host").and_then(|v| v.as_str()) == Some(host.as_str())
+        && table.get("protocol").and_then(|v| v.as_str()) == Some(protocol.as_str())
+        && table.get("path").and_then(|v| v.as_str()) == path.as_deref()
+}
+
+/// Points a `server_configs` entry at `address`, adding or removing its `path` key as needed.
+fn write_server_target(table: &mut toml_edit::Table, address: &str) {
+    let (protocol, host) = normalize_server_target(address);
+    set_toml_value(table, "host", host);
+    set_toml_value(table, "protocol", protocol);
+    match normalize_server_path(address) {
+        Some(path) => set_toml_value(table, "path", path),
+        None => {
+            table.remove("path");
+        }
+    }
+}
+
+fn update_cli_server_target(cli_toml: &mut DocumentMut, profile_name: &str, address: &str) {
+    set_toml_value(cli_toml, "default_server", profile_name);
+
+    if let Some(array) = server_configs_mut(cli_toml) {
+        for table in array.iter_mut() {
+            if table.get("nickname").and_then(|v| v.as_str()) == Some(profile_name) {
+                write_server_target(table, address);
+                return;
+            }
+        }
+        let mut table = toml_edit::Table::new();
+        table["nickname"] = Item::Value(profile_name.into());
+        write_server_target(&mut table, address);
+        array.push(table);
+    }
+}
+
+/// Sets `key` to `value`, keeping the comments and spacing around an existing value.
+fn set_toml_value(table: &mut toml_edit::Table, key: &str, value: impl Into<toml_edit::Value>) {
+    let mut value = value.into();
+    if let Some(existing) = table.get(key).and_then(|item| item.as_value()) {
+        *value.decor_mut() = existing.decor().clone();
+    }
+    table[key] = Item::Value(value);
+}
+
+/// Makes `profile_name` the active session of `cli_toml`, pointed at `address`.
+fn apply_profile_to_cli_toml(
+    settings: &AppSettings,
+    cli_toml: &mut DocumentMut,
+    profiles: &UserProfiles,
+    profile_name: &str,
+    address: &str,
+) {
+    let profile = &profiles.0[profile_name];
+    set_toml_value(cli_toml, &settings.cli_token_key, &profile.token);
+    set_toml_value(cli_toml, "default_host", address);
+    // Sync first so the active entry keeps an overridden address
+    sync_server_configs_from_profiles(cli_toml, profiles);
+    update_cli_server_target(cli_toml, profile_name, address);
+}
+
+/// A cli.toml value that differs between two documents; `None` means unset.
+#[derive(Debug, PartialEq, Eq)]
+struct FieldChange {
+    field: String,
+    current: Option<String>,
+    proposed: Option<String>,
+}
+
+/// The `host`, `protocol` and `path` of each `server_configs` entry, by nickname.
+fn server_config_fields(
+    cli_toml: &DocumentMut,
+) -> BTreeMap<String, BTreeMap<&'static str, String>> {
+    let mut entries = BTreeMap::new();
+    if let Some(array) = cli_toml
+        .get("server_configs")
+        .and_then(|item| item.as_array_of_tables())
+    {
+        for table in array.iter() {
+            let Some(nickname) = table.get("nickname").and_then(|v| v.as_str()) else {
+                continue;
+            };
+            let fields = ["host", "protocol", "path"]
+                .into_iter()
+                .filter_map(|key| {
+                    let value = table.get(key).and_then(|v| v.as_str())?;
+                    Some((key, value.to_string()))
+                })
+                .collect();
+            entries.insert(nickname.to_string(), fields);
+        }
+    }
+    entries
+}
+
+/// Compares the token (masked), `default_host`, `default_server` and every
+/// `server_configs` field of two cli.toml documents.
+fn cli_toml_changes(
+    current: &DocumentMut,
+    proposed: &DocumentMut,
+    token_key: &str,
+    mask_reveal: usize,
+) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    let mut compare = |field: String, current: Option<String>, proposed: Option<String>| {
+        if current != proposed {
+            changes.push(FieldChange {
+                field,
+                current,
+                proposed,
+            });
+        }
+    };
+    let string_field = |doc: &DocumentMut, key: &str| {
+        doc.get(key)
+            .and_then(|item| item.as_str())
+            .map(|value| value.to_string())
+    };
+    let token = |doc: &DocumentMut| {
+        string_field(doc, token_key).map(|token| mask_token(&token, mask_reveal))
+    };
+    compare(token_key.to_string(), token(current), token(proposed));
+    for key in ["default_host", "default_server"] {
+        compare(
+            key.to_string(),
+            string_field(current, key),
+            string_field(proposed, key),
+        );
+    }
+
+    let current_configs = server_config_fields(current);
+    let proposed_configs = server_config_fields(proposed);
+    let nicknames: BTreeSet<&String> = current_configs
+        .keys()
+        .chain(proposed_configs.keys())
+        .collect();
+    for nickname in nicknames {
+        for key in ["host", "protocol", "path"] {
+            let value = |configs: &BTreeMap<String, BTreeMap<&str, String>>| {
+                configs
+                    .get(nickname)
+                    .and_then(|fields| fields.get(key))
+                    .cloned()
+            };
+            compare(
+                format!("server_configs[{}].{}", nickname, key),
+                value(&current_configs),
+                value(&proposed_configs),
+            );
+        }
+    }
+    changes
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SyncCounts {
+    added: usize,
+    updated: usize,
+}
+
+/// Adds or updates a `server_configs` entry for every profile, returning how many
+/// entries were added and how many existing ones changed.
+fn sync_server_configs_from_profiles(
+    cli_toml: &mut DocumentMut,
+    profiles: &UserProfiles,
+) -> SyncCounts {
+    let mut counts = SyncCounts::default();
+    if let Some(array) = server_configs_mut(cli_toml) {
+        for (name, profile) in profiles.0.iter() {
+            let mut found = false;
+            for table in array.iter_mut() {
+                if table.get("nickname").and_then(|v| v.as_str()) == Some(name.as_str()) {
+                    if !server_target_matches(table, &profile.address) {
+                        write_server_target(table, &profile.address);
+                        counts.updated += 1;
+                    }
+                    found = true;
+                    break;
+                }
+            }
+            if !found {
+                let mut table = toml_edit::Table::new();
+                table["nickname"] = Item::Value(name.clone().into());
+                write_server_target(&mut table, &profile.address);
+                array.push(table);
+                counts.added += 1;
+            }
+        }
+    }
+    counts
+}
+
+/// Removes `server_configs` entries whose nickname is not a stored profile and
+/// returns their nicknames. SpacetimeDB's built-in servers, the current
+/// `default_server` and entries without a nickname are kept.
+fn prune_stale_server_configs(cli_toml: &mut DocumentMut, profiles: &UserProfiles) -> Vec<String> {
+    let default_server = cli_toml
+        .get("default_server")
+        .and_then(|item| item.as_str())
+        .map(|value| value.to_string());
+    let mut removed = Vec::new();
+    if let Some(array) = cli_toml
+        .get_mut("server_configs")
+        .and_then(|item| item.as_array_of_tables_mut())
+    {
+        array.retain(|table| {
+            let Some(nickname) = table.get("nickname").and_then(|v| v.as_str()) else {
+                return true;
+            };
+            let keep = profiles.0.contains_key(nickname)
+                || BUILTIN_SERVER_NICKNAMES.contains(&nickname)
+                || default_server.as_deref() == Some(nickname);
+            if !keep {
+                removed.push(nickname.to_string());
+            }
+            keep
+        });
+    }
+    removed
+}
+
+/// Removes the `server_configs` entries that target `address`, except built-in
+/// servers and entries named after a remaining profile, and clears
+/// `default_host`/`default_server` when they point at it. Returns the removed
+/// nicknames and whether the defaults were cleared.
+fn forget_environment(
+    cli_toml: &mut DocumentMut,
+    profiles: &UserProfiles,
+    address: &str,
+) -> (Vec<String>, bool) {
+    let mut removed = Vec::new();
+    if let Some(array) = cli_toml
+        .get_mut("server_configs")
+        .and_then(|item| item.as_array_of_tables_mut())
+    {
+        array.retain(|table| {
+            let Some(nickname) = table.get("nickname").and_then(|v| v.as_str()) else {
+                return true;
+            };
+            let keep = !server_target_matches(table, address)
+                || profiles.0.contains_key(nickname)
+                || BUILTIN_SERVER_NICKNAMES.contains(&nickname);
+            if !keep {
+                removed.push(nickname.to_string());
+            }
+            keep
+        });
+    }
+
+    let host_matches = cli_toml.get("default_host").and_then(|item| item.as_str()) == Some(address);
+    let server_removed = cli_toml
+        .get("default_server")
+        .and_then(|item| item.as_str())
+        .is_some_and(|nickname| removed.iter().any(|name| name == nickname));
+    if host_matches {
+        cli_toml.remove("default_host");
+    }
+    if host_matches || server_removed {
+        cli_toml.remove("default_server");
+    }
+    (removed, host_matches || server_removed)
+}
+
+/// Returns the first standard proxy environment variable that is set, with its value.
+fn proxy_from_env() -> Option<(&'static str, String)> {
+    [
+        "HTTPS_PROXY",
+        "https_proxy",
+        "HTTP_PROXY",
+        "http_proxy",
+        "ALL_PROXY",
+        "all_proxy",
+    ]
+    .into_iter()
+    .find_map(|name| {
+        std::env::var(name)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map(|value| (name, value))
+    })
+}
+
+fn build_http_client(settings: &AppSettings) -> Result<BlockingHttpClient> {
+    let mut builder = BlockingHttpClient::builder()
+        .timeout(std::time::Duration::from_secs(settings.http_timeout_secs));
+    // reqwest honors the proxy environment variables unless a proxy is set explicitly.
+    if let Some(proxy) = &settings.proxy {
+        verbose!("Using proxy {} (from {}).", proxy, DEFAULT_CONFIG_FILENAME);
+        builder = builder.proxy(
+            reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy URL '{}'", proxy))?,
+        );
+    } else if let Some((name, value)) = proxy_from_env() {
+        verbose!("Using proxy {} (from {}).", value, name);
+    }
+    if let Some(ca_cert_path) = &settings.ca_cert_path {
+        let home_dir = dirs::home_dir().context("Failed to get home directory")?;
+        let path = home_dir.join(ca_cert_path);
+        verbose!("Trusting CA certificate {:?}", path);
+        let pem =
+            fs::read(&path).with_context(|| format!("Failed to read CA certificate {:?}", path))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid PEM CA certificate {:?}", path))?;
+        if certificates.is_empty() {
+            anyhow::bail!("No PEM certificate found in {:?}", path);
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if insecure_tls() {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    builder.build().context("Failed to build HTTP client")
+}
+
+/// The HTTP client shared by every request in this run, built from `settings`
+/// on first use so timeout, proxy and CA settings apply once and connections
+/// are pooled.
+fn http_client(settings: &AppSettings) -> Result<&'static BlockingHttpClient> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client);
+    }
+    let client = build_http_client(settings)?;
+    Ok(HTTP_CLIENT.get_or_init(|| client))
+}
+
+fn fetch_server_issued_token(settings: &AppSettings, address: &str) -> Result<String> {
+    let base = normalize_identity_base(address);
+    let url = format!("{}/v1/identity", base);
+    if dry_run() {
+        println!("(dry run) Would request a new token from {}.", url);
+        return Ok(DRY_RUN_TOKEN.to_string());
+    }
+    let client = http_client(settings)?;
+    verbose!("POST {}", url);
+    let response = client
+        .post(&url)
+        .header(CONTENT_LENGTH, "0")
+        .send()
+        .with_context(|| format!("Failed to call {}", url))?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Server-issued login failed with status {} for {}",
+            response.status(),
+            url
+        );
+    }
+    let identity = response
+        .json::<IdentityResponse>()
+        .context("Failed to parse identity response")?;
+    if identity.token.trim().is_empty() {
+        anyhow::bail!("Identity response did not include a token.");
+    }
+    Ok(identity.token)
+}
+
+/// Whether tokens for this address can be minted with a direct identity request.
+/// Maincloud only issues tokens through the browser login.
+fn supports_server_issued_login(address: &str) -> bool {
+    if local_port(address).is_some() {
+        return true;
+    }
+    let (_, host) = normalize_server_target(address);
+    let host = host.split(':').next().unwrap_or_default();
+    !host.is_empty() && host != "maincloud" && host != "maincloud.spacetimedb.com"
+}
+
+/// Sends an unauthenticated `GET {base}/v1/ping` and returns the status and round-trip time.
+fn ping_server(
+    settings: &AppSettings,
+    address: &str,
+) -> Result<(reqwest::StatusCode, std::time::Duration)> {
+    let url = format!("{}/v1/ping", normalize_identity_base(address));
+    let client = http_client(settings)?;
+    verbose!("GET {}", url);
+    let started = std::time::Instant::now();
+    let response = client
+        .get(&url)
+        .send()
+        .with_context(|| format!("Server at '{}' is unreachable ({})", address, url))?;
+    Ok((response.status(), started.elapsed()))
+}
+
+/// Checks that the server accepts `token` and returns the identity it belongs to.
+fn verify_token_identity(settings: &AppSettings, address: &str, token: &str) -> Result<String> {
+    let claims = decode_jwt_claims(token).context("Token is not a decodable JWT")?;
+    let identity = claims
+        .get("hex_identity")
+        .and_then(|value| value.as_str())
+        .context("Token claims do not include 'hex_identity'.")?
+        .to_string();
+    let base = normalize_identity_base(address);
+    let url = format!("{}/v1/identity/{}/verify", base, identity);
+    let client = http_client(settings)?;
+    verbose!("GET {}", url);
+    let response = client
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .with_context(|| format!("Failed to call {}", url))?;
+    // Kept as a reqwest error so `verify` can tell a refused token (401/403) from other failures.
+    response
+        .error_for_status()
+        .with_context(|| format!("Server did not accept the token at {}", url))?;
+    Ok(identity)
+}
+
+/// Outcome of checking one profile's token with `verify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VerifyStatus {
+    Ok,
+    /// The server refused the token (401 or 403)
+    Rejected,
+    /// The server could not be reached
+    Unreachable,
+    /// Any other failure: an unusable token, a server error or an unexpected response
+    Error,
+}
+
+impl VerifyStatus {
+    fn from_result(result: &Result<String>) -> Self {
+        let Err(error) = result else {
+            return VerifyStatus::Ok;
+        };
+        let status = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+            .and_then(reqwest::Error::status);
+        match status {
+            Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN) => {
+                VerifyStatus::Rejected
+            }
+            None if ErrorCategory::classify(error) == ErrorCategory::Network => {
+                VerifyStatus::Unreachable
+            }
+            _ => VerifyStatus::Error,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            VerifyStatus::Ok => "ok",
+            VerifyStatus::Rejected => "rejected",
+            VerifyStatus::Unreachable => "unreachable",
+            VerifyStatus::Error => "error",
+        }
+    }
+}
+
+/// A failure whose category is known where it is raised, such as a missing
+/// profile or rejected input. The message is shown as is.
+#[derive(Debug)]
+struct CategorizedError {
+    category: ErrorCategory,
+    message: String,
+}
+
+impl std::fmt::Display for CategorizedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CategorizedError {}
+
+/// Broad failure categories, used for JSON error output and exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorCategory {
+    General,
+    /// A named profile, backup or environment does not exist
+    NotFound,
+    /// Input was rejected before anything was changed
+    Invalid,
+    Io,
+    Network,
+    Parse,
+}
+
+impl ErrorCategory {
+    /// An error of this category with `message`, e.g. for `anyhow::bail!`.
+    fn error(self, message: impl Into<String>) -> CategorizedError {
+        CategorizedError {
+            category: self,
+            message: message.into(),
+        }
+    }
+
+    fn classify(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(categorized) = cause.downcast_ref::<CategorizedError>() {
+                return categorized.category;
+            }
+            // An HTTP error status means the server was reached.
+            if cause
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|error| error.status().is_none())
+            {
+                return ErrorCategory::Network;
+            }
+            if cause.is::<std::io::Error>() {
+                return ErrorCategory::Io;
+            }
+            if cause.is::<toml::de::Error>()
+                || cause.is::<toml_edit::TomlError>()
+                || cause.is::<serde_json::Error>()
+            {
+                return ErrorCategory::Parse;
+            }
+        }
+        ErrorCategory::General
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ErrorCategory::General => "general",
+            ErrorCategory::NotFound => "not_found",
+            ErrorCategory::Invalid => "invalid",
+            ErrorCategory::Io => "io",
+            ErrorCategory::Network => "network",
+            ErrorCategory::Parse => "parse",
+        }
+    }
+
+    fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::General => 1,
+            ErrorCategory::NotFound => 6,
+            ErrorCategory::Invalid => 7,
+            ErrorCategory::Io => 3,
+            ErrorCategory::Network => 4,
+            ErrorCategory::Parse => 5,
+        }
+    }
+}
+
+/// A failure the command has already reported (e.g. in its `--json` output, or
+/// by the command `exec` ran), so `main` only exits with this code.
+#[derive(Debug)]
+struct AlreadyReported(i32);
+
+impl std::fmt::Display for AlreadyReported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the command failed with exit code {}", self.0)
+    }
+}
+
+impl std::error::Error for AlreadyReported {}
+
+/// The failure object for `--json` and `--json-errors`: the category, the
+/// outermost message, then the messages of the errors it wraps, down to the root cause.
+fn error_json(error: &anyhow::Error, category: ErrorCategory) -> serde_json::Value {
+    let context: Vec<String> = error
+        .chain()
+        .skip(1)
+        .map(|cause| cause.to_string())
+        .collect();
+    serde_json::json!({
+        "error": {
+            "category": category.as_str(),
+            "message": error.to_string(),
+            "context": context,
+        }
+    })
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let json = cli.output_mode() == OutputMode::Json;
+    let json_errors = cli.json_errors;
+    let result = run(cli);
+    if dry_run() && !json && result.is_ok() {
+        println!("(dry run, no changes written)");
+    }
+    if let Err(error) = result {
+        if let Some(AlreadyReported(exit_code)) = error.downcast_ref::<AlreadyReported>() {
+            std::process::exit(*exit_code);
+        }
+        let category = ErrorCategory::classify(&error);
+        // `--json-errors` only moves the object to stderr, next to human-readable output.
+        if json_errors {
+            eprintln!("{}", error_json(&error, category));
+            std::process::exit(category.exit_code());
+        }
+        if json {
+            println!("{}", error_json(&error, category));
+            std::process::exit(category.exit_code());
+        }
+        eprintln!("Error: {:?}", error);
+        std::process::exit(category.exit_code());
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let json = cli.output_mode() == OutputMode::Json;
+    let table = cli.output_mode() == OutputMode::Table;
+    let porcelain = cli.porcelain;
+    if porcelain && !cli.command.accepts_porcelain() {
+        anyhow::bail!(
+            ErrorCategory::Invalid.error("--porcelain only applies to current and env current.")
+        );
+    }
+    // Porcelain output must be the value alone, so notices are silenced too.
+    let verbosity = if cli.quiet || porcelain {
+        Verbosity::Quiet
+    } else if cli.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+    let _ = VERBOSITY.set(verbosity);
+    let _ = DRY_RUN.set(cli.dry_run);
+    let _ = JSON_OUTPUT.set(json);
+    let _ = INSECURE_TLS.set(cli.insecure);
+    if cli.insecure {
+        eprintln!(
+            "WARNING: --insecure disables TLS certificate verification. Anyone on the network path can impersonate the server and capture tokens. Use --ca-cert instead and do not leave this on."
+        );
+    }
+    if let Some(dir) = cli.config_dir {
+        let _ = CONFIG_DIR_OVERRIDE.set(dir);
+    }
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    if cli.no_color || no_color_env {
+        dialoguer::console::set_colors_enabled(false);
+        dialoguer::console::set_colors_enabled_stderr(false);
+    }
+    if let Commands::CompleteProfiles = cli.command {
+        print_profile_names_for_completion();
+        return Ok(());
+    }
+    let mut settings = load_app_settings().context("Failed to load application settings")?;
+    let _ = MASK_REVEAL.set(settings.mask_reveal);
+    if let Some(ca_cert) = &cli.ca_cert {
+        // Made absolute so it is not resolved against home like the setting.
+        let ca_cert = std::env::current_dir()?.join(ca_cert);
+        settings.ca_cert_path = Some(ca_cert.to_string_lossy().into_owned());
+    }
+    if cli.local {
+        settings.local_profiles = true;
+    }
+    // Held until `run` returns; dropping the file releases the lock.
+    let _lock = if cli.command.is_read_only() {
+        None
+    } else {
+        Some(lock_app_config_dir()?)
+    };
+    if !cli.command.is_read_only() {
+        check_external_cli_toml_change(&settings)?;
+    }
+    let profile_scope = cli.profile.clone();
+    if let Some(name) = &profile_scope {
+        if !cli.command.accepts_profile_scope() {
+            anyhow::bail!(ErrorCategory::Invalid
+                .error("--profile only applies to show, ping, export, copy-token and env use."));
+        }
+        if !read_profiles(&settings)?.0.contains_key(name) {
+            anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                "Profile '{}' (from --profile) not found in {}.",
+                name, settings.profiles_filename
+            )));
+        }
+    }
+
+    match cli.command {
+        Commands::Set(args) => {
+            let token = match (args.token, &args.token_file) {
+                (Some(token), _) => token,
+                (None, Some(path)) => read_token_file(path)?,
+                (None, None) => anyhow::bail!("Provide a token or --token-file."),
+            };
+            if !looks_like_token(&token) {
+                if args.strict {
+                    anyhow::bail!(ErrorCategory::Invalid.error(
+                        "The token does not look like a JWT (empty, contains whitespace, or not three base64url segments). Refusing to store it."
+                    ));
+                }
+                eprintln!(
+                    "Warning: the token does not look like a JWT (empty, contains whitespace, or not three base64url segments). It may be truncated or mis-pasted."
+                );
+            }
+            let mut profiles = read_profiles(&settings)?;
+            let address = args.address.unwrap_or_else(|| {
+                get_current_environment(&settings)
+                    .unwrap_or_default()
+                    .unwrap_or_else(|| "local".to_string())
+            });
+            let address = resolve_address_alias(&settings, &address);
+            let address = apply_address_protocol(&address, args.protocol);
+            validate_address(&address)?;
+            if let Some(existing) = profiles.0.get(&args.profile_name) {
+                let unchanged = existing.token == token && existing.address == address;
+                if !args.force && !unchanged {
+                    println!("Profile '{}' already exists:", args.profile_name);
+                    println!("  address: {} -> {}", existing.address, address);
+                    println!(
+                        "  token:   {} -> {}",
+                        mask_token(&existing.token, settings.mask_reveal),
+                        mask_token(&token, settings.mask_reveal)
+                    );
+                    if !std::io::stdin().is_terminal() {
+                        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                            "Refusing to overwrite profile '{}' without confirmation. Use --force to overwrite.",
+                            args.profile_name
+                        )));
+                    }
+                    let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                        .with_prompt(format!("Overwrite profile '{}'?", args.profile_name))
+                        .default(false)
+                        .interact()?;
+                    if !confirmation {
+                        println!("Set cancelled.");
+                        return Ok(());
+                    }
+                }
+            }
+            if args.no_activate {
+                store_profile(&mut profiles, &args.profile_name, token, address);
+                write_profiles(&settings, &profiles)?;
+                info!(
+                    "Profile '{}' saved/updated in {}; {} was not changed.",
+                    args.profile_name, settings.profiles_filename, settings.cli_config_filename
+                );
+                return Ok(());
+            }
+            store_and_activate_profile(
+                &settings,
+                &mut profiles,
+                &args.profile_name,
+                token,
+                address,
+                "set",
+            )?;
+            info!(
+                "Profile '{}' saved/updated in {}.",
+                args.profile_name, settings.profiles_filename
+            );
+            info!(
+                "Profile '{}' also set as active in {}.",
+                args.profile_name, settings.cli_config_filename
+            );
+        }
+        Commands::Add => {
+            if !std::io::stdin().is_terminal() {
+                anyhow::bail!("'add' is interactive. Use 'set' in scripts.");
+            }
+            let mut profiles = read_profiles(&settings)?;
+            let theme = prompt_theme();
+            let ask_name = |prompt: &str| -> Result<String> {
+                let name: String = Input::with_theme(theme.as_ref())
+                    .with_prompt(prompt)
+                    .validate_with(|input: &String| {
+                        if input.trim().is_empty() || input.contains(char::is_whitespace) {
+                            Err("Profile names must be non-empty and contain no spaces.")
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .interact_text()?;
+                Ok(name)
+            };
+
+            let mut profile_name = ask_name("Profile name")?;
+            while profiles.0.contains_key(&profile_name) {
+                let choice = Select::with_theme(theme.as_ref())
+                    .with_prompt(format!("Profile '{}' already exists", profile_name))
+                    .items(&["Overwrite it", "Choose a different name", "Cancel"])
+                    .default(1)
+                    .interact()?;
+                match choice {
+                    0 => break,
+                    1 => profile_name = ask_name("New profile name")?,
+                    _ => {
+                        println!("Add cancelled.");
+                        return Ok(());
+                    }
+                }
+            }
+
+            let address: String = Input::with_theme(theme.as_ref())
+                .with_prompt("Server address")
+                .default("local".to_string())
+                .validate_with(|input: &String| validate_address(input).map_err(|e| e.to_string()))
+                .interact_text()?;
+
+            let token = Password::with_theme(theme.as_ref())
+                .with_prompt("Token")
+                .validate_with(|input: &String| {
+                    if input.trim().is_empty() {
+                        Err("The token must not be empty.")
+                    } else {
+                        Ok(())
+                    }
+                })
+                .interact()?;
+            let token = token.trim().to_string();
+            if !looks_like_token(&token) {
+                let keep = Confirm::with_theme(theme.as_ref())
+                    .with_prompt("The token does not look like a JWT. Save it anyway?")
+                    .default(false)
+                    .interact()?;
+                if !keep {
+                    println!("Add cancelled.");
+                    return Ok(());
+                }
+            }
+
+            store_and_activate_profile(
+                &settings,
+                &mut profiles,
+                &profile_name,
+                token,
+                address,
+                "add",
+            )?;
+            info!(
+                "Profile '{}' saved in {} and set as active in {}.",
+                profile_name, settings.profiles_filename, settings.cli_config_filename
+            );
+        }
+        Commands::Switch(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            // Only filter when an address is explicitly provided; otherwise show all profiles
+            let env_filter = args.address.clone();
+
+            let mut address_override = None;
+            let requested_name = if args.previous || args.profile_name.as_deref() == Some("-") {
+                let history = read_profile_history()?;
+                match history.previous {
+                    Some(name) if profiles.0.contains_key(&name) => Some(name),
+                    Some(name) => {
+                        println!(
+                            "The previous profile '{}' no longer exists in {}. Switch with 'switch <PROFILE_NAME>' instead.",
+                            name, settings.profiles_filename
+                        );
+                        return Ok(());
+                    }
+                    None => {
+                        println!(
+                            "No previous profile recorded yet. It is remembered once you switch between two profiles."
+                        );
+                        return Ok(());
+                    }
+                }
+            } else {
+                args.profile_name
+            };
+            let profile_name_to_switch = match requested_name {
+                Some(name) => {
+                    if let Some(filter) = &env_filter {
+                        if let Some(profile) = profiles.0.get(&name) {
+                            if &profile.address != filter {
+                                let known = token_addresses(&profiles, &profile.token);
+                                if !known.contains(filter) {
+                                    anyhow::bail!(
+                                        "Profile '{}' has never been used with '{}'. Known addresses for its token: {}.",
+                                        name,
+                                        filter,
+                                        known.join(", ")
+                                    );
+                                }
+                                address_override = Some(filter.clone());
+                            }
+                        }
+                    }
+                    name
+                }
+                None if env_filter.is_none()
+                    && args.fuzzy.is_none()
+                    && !args.pick
+                    && settings.default_profile.is_some() =>
+                {
+                    let name = settings.default_profile.clone().unwrap_or_default();
+                    if !profiles.0.contains_key(&name) {
+                        anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                            "Default profile '{}' not found. Use 'set-default' to pick another or '--pick' to choose interactively.",
+                            name
+                        )));
+                    }
+                    info!("Using default profile '{}'.", name);
+                    name
+                }
+                None => {
+                    let mut filtered_profiles: HashMap<String, Profile> = profiles.0.clone();
+                    if let Some(env) = &env_filter {
+                        info!("Environment filter: {}", env);
+                        filtered_profiles.retain(|_, profile| &profile.address == env);
+                    }
+
+                    if filtered_profiles.is_empty() {
+                        println!(
+                            "No profiles found in {}{}.",
+                            settings.profiles_filename,
+                            env_filter
+                                .as_ref()
+                                .map(|env| format!(" for environment '{}'", env))
+                                .unwrap_or_default()
+                        );
+                        anyhow::bail!(
+                            ErrorCategory::NotFound.error("No profiles available to switch.")
+                        );
+                    }
+                    let mut profile_names: Vec<String> =
+                        filtered_profiles.keys().cloned().collect();
+                    profile_names.sort();
+                    if let Some(query) = &args.fuzzy {
+                        let name = select_profile_fuzzy(&profile_names, query)?;
+                        info!("Matched '{}' to profile '{}'.", query, name);
+                        name
+                    } else {
+                        let selection = Select::with_theme(prompt_theme().as_ref())
+                            .with_prompt("Select a profile to switch to")
+                            .items(&profile_names)
+                            .default(0)
+                            .interact_opt()?
+                            .context("No profile selected or selection cancelled.")?;
+
+                        profile_names[selection].clone()
+                    }
+                }
+            };
+
+            if profiles.0.contains_key(&profile_name_to_switch) {
+                activate_profile_at(
+                    &settings,
+                    &mut profiles,
+                    &profile_name_to_switch,
+                    address_override.as_deref(),
+                    "switch",
+                )?;
+                info!(
+                    "Switched active profile to '{}' (from {}) in {}.",
+                    profile_name_to_switch,
+                    settings.profiles_filename,
+                    settings.cli_config_filename
+                );
+                if let Some(address) = &address_override {
+                    info!("Using address '{}' for this session.", address);
+                }
+            } else {
+                println!(
+                    "Profile '{}' not found in {}. Cannot switch.", // Renamed
+                    profile_name_to_switch,
+                    settings.profiles_filename // Renamed
+                );
+                println!("Available profiles: {:?}", profiles.0.keys()); // Renamed
+                anyhow::bail!(ErrorCategory::NotFound
+                    .error("Profile not found in profiles file for switching."));
+            }
+        }
+        Commands::Logout => {
+            let cli_toml_path = get_cli_toml_path(&settings)?;
+            if !cli_toml_path.exists() {
+                println!(
+                    "{} not found. No active session to log out of.",
+                    settings.cli_config_filename
+                );
+                return Ok(());
+            }
+            let profiles = read_profiles(&settings)?;
+            let mut history = read_profile_history().unwrap_or_default();
+            let active = active_profile_name(&settings, &profiles, &history);
+            let mut cli_toml = read_cli_toml(&settings)?;
+            let mut removed = false;
+            for key in [
+                settings.cli_token_key.as_str(),
+                "default_host",
+                "default_server",
+            ] {
+                removed |= cli_toml.remove(key).is_some();
+            }
+            if !removed {
+                println!(
+                    "No active session in {}. Nothing to log out of.",
+                    settings.cli_config_filename
+                );
+                return Ok(());
+            }
+            write_cli_toml(&settings, &cli_toml)?;
+
+            match active {
+                Some(name) => {
+                    info!("Logged out of profile '{}'. The profile is kept.", name);
+                    // `switch -` can return to it.
+                    history.previous = Some(name);
+                    history.current = None;
+                    if let Err(e) = write_profile_history(&history) {
+                        eprintln!(
+                            "Warning: failed to write {}: {:#}",
+                            PROFILE_HISTORY_FILENAME, e
+                        );
+                    }
+                }
+                None => info!(
+                    "Logged out. The active token did not belong to any profile in {}.",
+                    settings.profiles_filename
+                ),
+            }
+        }
+        Commands::Admin => {
+            let admin_profile_name = settings.admin_profile.clone();
+            let mut profiles = read_profiles(&settings)?;
+            if profiles.0.contains_key(&admin_profile_name) {
+                activate_profile(&settings, &mut profiles, &admin_profile_name, "admin")?;
+                info!(
+                    "Switched active profile to ADMIN '{}' (from {}) in {}.",
+                    admin_profile_name, settings.profiles_filename, settings.cli_config_filename
+                );
+            } else {
+                println!(
+                    "ADMIN profile ('{}') not found in {}. Cannot switch.", // Renamed
+                    admin_profile_name,
+                    settings.profiles_filename // Renamed
+                );
+                println!(
+                    "Ensure a profile named '{}' exists with a valid token, or change admin_profile in {}.",
+                    admin_profile_name, DEFAULT_CONFIG_FILENAME
+                );
+                anyhow::bail!(ErrorCategory::NotFound
+                    .error(format!("Admin profile '{}' not found.", admin_profile_name)));
+            }
+        }
+        Commands::Save(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            if args.overwrite && !args.force {
+                if let Some(existing) = profiles.0.get(&args.profile_name) {
+                    if !std::io::stdin().is_terminal() {
+                        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                            "Refusing to overwrite profile '{}' without confirmation. Use --force to overwrite.",
+                            args.profile_name
+                        )));
+                    }
+                    let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                        .with_prompt(format!(
+                            "Replace profile '{}' (address: {}) with the current session?",
+                            args.profile_name, existing.address
+                        ))
+                        .default(false)
+                        .interact()?;
+                    if !confirmation {
+                        println!("Save cancelled.");
+                        return Ok(());
+                    }
+                }
+            }
+            save_active_session(&settings, &mut profiles, &args.profile_name, args.overwrite)?;
+        }
+        Commands::Reset(args) => {
+            if !args.force {
+                let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                    .with_prompt(format!(
+                        "Are you sure you want to reset {}? This will delete all profiles.",
+                        settings.profiles_filename
+                    ))
+                    .interact()?;
+                if !confirmation {
+                    println!("Reset cancelled.");
+                    return Ok(());
+                }
+            }
+            let backup_path = backup_profiles_file(&settings)?;
+            let profiles = UserProfiles::default();
+            write_profiles(&settings, &profiles)?;
+            match backup_path {
+                Some(backup_path) => info!(
+                    "{} has been reset. Previous profiles were backed up to {:?}.",
+                    settings.profiles_filename, backup_path
+                ),
+                None => info!("{} has been reset.", settings.profiles_filename),
+            }
+        }
+        Commands::Create(args) => {
+            let mut profiles = read_profiles(&settings)?; // Renamed
+            if profiles.0.contains_key(&args.profile_name) {
+                // Renamed
+                anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                    "Profile '{}' already exists in {}. Cannot create.", // Renamed
+                    args.profile_name,                                   // Renamed
+                    settings.profiles_filename                           // Renamed
+                )));
+            }
+
+            let address = args.address.unwrap_or_else(|| "local".to_string());
+            let address = resolve_address_alias(&settings, &address);
+            let address = apply_address_protocol(&address, args.protocol);
+            validate_address(&address)?;
+            if let Some(timeout) = args.timeout {
+                // Only for this run; config.toml is not rewritten.
+                settings.http_timeout_secs = timeout;
+            }
+            let supplied_token = match (args.token, &args.token_file) {
+                (Some(token), _) => Some(token),
+                (None, Some(path)) => Some(read_token_file(path)?),
+                (None, None) => None,
+            };
+            let login_mode = match supplied_token {
+                Some(_) => None,
+                None => Some(args.login_mode.unwrap_or(LoginMode::ServerIssued)),
+            };
+            // With --verify, checks the token before anything is saved.
+            let verify_new_token = |token: &str| -> Result<()> {
+                if args.verify && !dry_run() {
+                    let identity =
+                        verify_token_identity(&settings, &address, token).with_context(|| {
+                            format!(
+                                "Token verification failed; profile '{}' was not saved.",
+                                args.profile_name
+                            )
+                        })?;
+                    info!("Verified token: server resolved identity {}.", identity);
+                }
+                Ok(())
+            };
+            let token = if let Some(token) = supplied_token {
+                info!(
+                    "Registering the given token as profile '{}' on environment '{}'.",
+                    args.profile_name, address
+                );
+                verify_new_token(&token)?;
+                token
+            } else {
+                let browser = args.login_mode == Some(LoginMode::Browser);
+                let flow = if browser {
+                    "'spacetime login' browser"
+                } else if local_port(&address).is_some() {
+                    "'spacetime login --server-issued-login'"
+                } else {
+                    "server-issued"
+                };
+                info!(
+                    "Will create profile '{}' on environment '{}' using the {} flow.",
+                    args.profile_name, address, flow
+                );
+                info!("This runs 'spacetime logout' first, ending the current CLI session.");
+                if !args.force && std::io::stdin().is_terminal() {
+                    let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                        .with_prompt("Continue?")
+                        .interact()?;
+                    if !confirmation {
+                        info!("Create cancelled.");
+                        return Ok(());
+                    }
+                }
+
+                // Keep the current session so a failed login does not leave the user logged out.
+                let cli_toml_path = get_cli_toml_path(&settings)?;
+                let previous_session = if args.force_logout || !cli_toml_path.exists() {
+                    None
+                } else {
+                    let content = fs::read_to_string(&cli_toml_path)
+                        .with_context(|| format!("Failed to read {:?}", cli_toml_path))?;
+                    Some(content)
+                };
+
+                let login = || -> Result<String> {
+                    run_external_command(SPACETIME_CLI_COMMAND, &["logout"], &[])
+                        .context("Failed to logout from SpacetimeDB CLI.")?;
+
+                    if browser || local_port(&address).is_some() {
+                        // The CLI knows `local` by nickname; other ports are passed as a URL.
+                        let login_server = if address == "local" {
+                            address.clone()
+                        } else {
+                            normalize_identity_base(&address)
+                        };
+                        let login_args = if browser {
+                            vec!["login"]
+                        } else {
+                            vec!["login", "--server-issued-login", &login_server]
+                        };
+                        info!(
+                            "Please follow the prompts from 'spacetime {}'",
+                            login_args.join(" ")
+                        );
+                        run_external_command(SPACETIME_CLI_COMMAND, &login_args, &[])
+                            .with_context(|| {
+                                format!("Failed during 'spacetime {}'", login_args.join(" "))
+                            })?;
+
+                        if !cli_toml_path.exists() {
+                            anyhow::bail!(
+                                "{} does not exist after login. Cannot save token.",
+                                settings.cli_config_filename
+                            );
+                        }
+                        let cli_toml = read_cli_toml(&settings)?;
+                        let token_item =
+                            cli_toml.get(&settings.cli_token_key).ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "Token key '{}' not found in {} after login.",
+                                    settings.cli_token_key,
+                                    settings.cli_config_filename
+                                )
+                            })?;
+                        token_item
+                            .as_str()
+                            .map(|value| value.to_string())
+                            .ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "Token key '{}' in {} is not a string after login.",
+                                    settings.cli_token_key,
+                                    settings.cli_config_filename
+                                )
+                            })
+                    } else {
+                        fetch_server_issued_token(&settings, &address)
+                    }
+                };
+
+                // The login may already have rewritten cli.toml, so a rejected token restores it too.
+                match login().and_then(|token| verify_new_token(&token).map(|()| token)) {
+                    Ok(token) => token,
+                    Err(e) => {
+                        if let Some(content) = previous_session {
+                            match write_file_atomically(&cli_toml_path, &content) {
+                                Ok(()) => info!(
+                                    "Create failed; the previous session in {} was restored.",
+                                    settings.cli_config_filename
+                                ),
+                                Err(restore_err) => eprintln!(
+                                    "Warning: failed to restore {:?}: {:#}",
+                                    cli_toml_path, restore_err
+                                ),
+                            }
+                        }
+                        return Err(e);
+                    }
+                }
+            };
+
+            let mut new_profile = Profile::new(token.clone(), address.clone());
+            new_profile.login_mode = login_mode;
+            profiles.0.insert(args.profile_name.clone(), new_profile);
+            activate_profile(&settings, &mut profiles, &args.profile_name, "create")?;
+
+            info!(
+                "Successfully created and saved profile '{}' in {}.",
+                args.profile_name, settings.profiles_filename
+            );
+
+            if !args.no_verify && !args.verify && !dry_run() {
+                match verify_token_identity(&settings, &address, &token) {
+                    Ok(identity) => info!(
+                        "Verified profile '{}': server resolved identity {}.",
+                        args.profile_name, identity
+                    ),
+                    Err(e) => {
+                        eprintln!(
+                            "WARNING: verification of profile '{}' failed: {:#}",
+                            args.profile_name, e
+                        );
+                        eprintln!(
+                            "The profile was saved, but its token may not work against '{}'.",
+                            address
+                        );
+                    }
+                }
+            }
+        }
+        Commands::List(args) => {
             let profiles = read_profiles(&settings)?;
-            // Only filter when an address is explicitly provided; otherwise show all profiles
-            let env_filter = args.address.clone();
+            let mut active_token_opt: Option<String> = None;
+            let current_env = if args.env {
+                get_current_environment(&settings).context("Failed to get current environment.")?
+            } else {
+                None
+            };
+
+            if let Ok(cli_toml_path) = get_cli_toml_path(&settings) {
+                if cli_toml_path.exists() {
+                    if let Ok(cli_toml_doc) = read_cli_toml(&settings) {
+                        if let Some(token_item) = cli_toml_doc.get(&settings.cli_token_key) {
+                            if let Some(token_str) = token_item.as_str() {
+                                active_token_opt = Some(token_str.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+
+            let mut profiles_to_display = profiles.0.clone();
+            if let Some(env) = current_env.as_ref().or(args.address.as_ref()) {
+                profiles_to_display.retain(|_, profile| &profile.address == env);
+            }
+            if let Some(tag) = &args.tag {
+                profiles_to_display.retain(|_, profile| profile.tags.contains(tag));
+            }
+            if args.stale {
+                let now = chrono::Utc::now();
+                profiles_to_display.retain(|_, profile| match token_expiry(&profile.token) {
+                    Some(exp) => exp <= now,
+                    None => args.include_unknown,
+                });
+            }
+
+            if args.addresses {
+                let addresses: BTreeSet<&str> = profiles_to_display
+                    .values()
+                    .map(|profile| profile.address.as_str())
+                    .collect();
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&addresses)?);
+                    return Ok(());
+                }
+                let current = get_current_environment(&settings).ok().flatten();
+                for address in addresses {
+                    if current.as_deref() == Some(address) {
+                        println!("{} (current)", address);
+                    } else {
+                        println!("{}", address);
+                    }
+                }
+                return Ok(());
+            }
+
+            let sorted_profile_names = sort_profile_names(&profiles_to_display, args.sort);
+            if json {
+                let entries: Vec<serde_json::Value> = sorted_profile_names
+                    .iter()
+                    .map(|profile_name| {
+                        profile_json(
+                            profile_name,
+                            &profiles_to_display[profile_name],
+                            active_token_opt.as_deref(),
+                        )
+                    })
+                    .collect();
+                if args.count {
+                    let wrapped = serde_json::json!({
+                        "count": entries.len(),
+                        "profiles": entries,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&wrapped)?);
+                } else {
+                    println!("{}", serde_json::to_string_pretty(&entries)?);
+                }
+                return Ok(());
+            }
+
+            if let Some(env) = &current_env {
+                println!("Current environment: {}", env);
+            }
+            if let Some(address) = &args.address {
+                println!("Address filter: {}", address);
+            }
+            if profiles_to_display.is_empty() {
+                println!("No profiles found in {}.", settings.profiles_filename);
+            } else {
+                if table {
+                    let rows: Vec<Vec<String>> = sorted_profile_names
+                        .iter()
+                        .map(|profile_name| {
+                            profile_table_row(
+                                profile_name,
+                                &profiles_to_display[profile_name],
+                                active_token_opt.as_deref(),
+                            )
+                        })
+                        .collect();
+                    let rendered = render_table(PROFILE_TABLE_HEADERS, &rows);
+                    for (line, row) in rendered
+                        .lines()
+                        .zip(std::iter::once(None).chain(rows.iter().map(Some)))
+                    {
+                        // Columns are padded before styling so escape codes do not skew them.
+                        match row {
+                            Some(row) if row[3] == "*" => {
+                                println!("{}", style(line).green().bold())
+                            }
+                            Some(row) if row[2].ends_with("(expired)") => {
+                                println!("{}", style(line).dim())
+                            }
+                            _ => println!("{}", line),
+                        }
+                    }
+                } else {
+                    println!("Available profiles in {}:", settings.profiles_filename);
+                    let widths = profile_line_widths(&profiles_to_display);
+                    for profile_name in &sorted_profile_names {
+                        if let Some(profile) = profiles_to_display.get(profile_name) {
+                            let mut line = format_profile_line(
+                                profile_name,
+                                profile,
+                                active_token_opt.as_deref(),
+                                widths,
+                            );
+                            if args.stale {
+                                line.push_str(match token_expiry(&profile.token) {
+                                    Some(_) => " [expired]",
+                                    None if decode_jwt_claims(&profile.token).is_err() => {
+                                        " [unparseable]"
+                                    }
+                                    None => " [no expiry]",
+                                });
+                            }
+                            println!("{}", line);
+                        }
+                    }
+                }
+                println!();
+                let environment = match current_env {
+                    Some(env) => Some(env),
+                    None => get_current_environment(&settings).ok().flatten(),
+                };
+                println!(
+                    "{}",
+                    list_summary(&profiles_to_display, &args, environment.as_deref())
+                );
+            }
+        }
+        Commands::Current(args) => {
+            if porcelain {
+                let profiles = read_profiles(&settings)?;
+                let history = read_profile_history().unwrap_or_default();
+                let name = active_profile_name(&settings, &profiles, &history);
+                println!("{}", name.unwrap_or_default());
+                return Ok(());
+            }
+            if json {
+                let profiles = read_profiles(&settings)?;
+                let active_token = read_active_token(&settings)?;
+                let history = read_profile_history().unwrap_or_default();
+                let active_name = active_profile_name(&settings, &profiles, &history);
+                let active_profile = active_name.as_deref().and_then(|name| profiles.0.get(name));
+                let address = get_current_environment(&settings)?
+                    .or_else(|| active_profile.map(|profile| profile.address.clone()));
+                let entry = serde_json::json!({
+                    "profile": active_name,
+                    "address": address,
+                    "token_masked": active_token.as_deref().map(|t| mask_token(t, settings.mask_reveal)),
+                    "expiry": active_token
+                        .as_deref()
+                        .and_then(token_expiry)
+                        .map(|exp| exp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+                });
+                println!("{}", serde_json::to_string_pretty(&entry)?);
+                return Ok(());
+            }
+            if table {
+                let profiles = read_profiles(&settings)?;
+                let Some(active_token) = read_active_token(&settings)? else {
+                    println!("No active token set.");
+                    return Ok(());
+                };
+                let row = match profiles
+                    .0
+                    .iter()
+                    .find(|(_, profile)| profile.token == active_token)
+                {
+                    Some((name, profile)) => profile_table_row(name, profile, Some(&active_token)),
+                    None => vec![
+                        "unknown".to_string(),
+                        get_current_environment(&settings)?
+                            .unwrap_or_else(|| "not set".to_string()),
+                        expiry_label(&active_token),
+                        "*".to_string(),
+                    ],
+                };
+                println!("{}", render_table(PROFILE_TABLE_HEADERS, &[row]));
+                return Ok(());
+            }
+            let cli_toml_path = get_cli_toml_path(&settings)?;
+            if !cli_toml_path.exists() {
+                println!(
+                    "{} not found. No active token set.",
+                    settings.cli_config_filename
+                );
+                return Ok(());
+            }
+            let cli_toml_doc = read_cli_toml(&settings)?;
+            if let Some(token_item) = cli_toml_doc.get(&settings.cli_token_key) {
+                if let Some(active_token_str) = token_item.as_str() {
+                    let profiles = read_profiles(&settings)?;
+                    let mut current_profile: Option<(String, Profile)> = None;
+                    for (profile_name, profile) in profiles.0.iter() {
+                        if profile.token == active_token_str {
+                            current_profile = Some((profile_name.clone(), profile.clone()));
+                            break;
+                        }
+                    }
+
+                    if let Some((name, profile)) = current_profile {
+                        println!("Current active profile: {}", name);
+                        println!("Address: {}", profile.address);
+                        if let Some(created_at) = &profile.created_at {
+                            println!("Created: {}", created_at);
+                        }
+                        if let Some(last_used) = &profile.last_used {
+                            println!("Last used: {}", last_used);
+                        }
+                        if let Some(note) = &profile.note {
+                            println!("Note: {}", note);
+                        }
+                    } else {
+                        println!(
+                            "Current active token is set, but not found under any profile name in {}.", // Renamed
+                            settings.profiles_filename // Renamed
+                        );
+                    }
+                    if args.full {
+                        println!("Active token: {}", active_token_str);
+                    } else {
+                        println!(
+                            "Active token: {}",
+                            mask_token(active_token_str, settings.mask_reveal)
+                        );
+                    }
+                    if let Err(e) = decode_jwt_claims(active_token_str) {
+                        eprintln!(
+                            "Warning: the active token does not parse as a JWT ({:#}). It may be truncated or corrupted.",
+                            e
+                        );
+                    }
+                } else {
+                    println!(
+                        "Active token key '{}' in {} is not a string.",
+                        settings.cli_token_key, settings.cli_config_filename
+                    );
+                }
+            } else {
+                println!(
+                    "No active token (key '{}') found in {}.",
+                    settings.cli_token_key, settings.cli_config_filename
+                );
+            }
+        }
+        Commands::Delete(DeleteArgs {
+            env: Some(env),
+            force,
+            ..
+        }) => {
+            let mut profiles = read_profiles(&settings)?;
+            let mut names: Vec<String> = profiles
+                .0
+                .iter()
+                .filter(|(_, profile)| profile.address == env)
+                .map(|(name, _)| name.clone())
+                .collect();
+            if names.is_empty() {
+                anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                    "No profiles found for environment '{}' in {}.",
+                    env, settings.profiles_filename
+                )));
+            }
+            names.sort();
+            println!("Profiles using environment '{}':", env);
+            for name in &names {
+                println!("- {}", name);
+            }
+            if !force {
+                let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                    .with_prompt(format!("Delete these {} profile(s)?", names.len()))
+                    .interact()?;
+                if !confirmation {
+                    println!("Deletion cancelled.");
+                    return Ok(());
+                }
+            }
+
+            let active_token = read_active_token(&settings).ok().flatten();
+            let mut active_deleted = None;
+            for name in &names {
+                if let Some(profile) = profiles.0.remove(name) {
+                    if active_token.as_deref() == Some(profile.token.as_str()) {
+                        active_deleted = Some(name.clone());
+                    }
+                }
+            }
+            write_profiles(&settings, &profiles)?;
+            info!(
+                "Deleted {} profile(s) for environment '{}' from {}.",
+                names.len(),
+                env,
+                settings.profiles_filename
+            );
+            let still_stored = active_token
+                .as_deref()
+                .is_some_and(|token| profiles.0.values().any(|profile| profile.token == token));
+            if let Some(name) = active_deleted.filter(|_| !still_stored) {
+                eprintln!(
+                    "Warning: the active session in {} still uses the token of deleted profile '{}'.",
+                    settings.cli_config_filename, name
+                );
+            }
+        }
+        Commands::Prune(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            let now = chrono::Utc::now();
+            let mut names: Vec<String> = profiles
+                .0
+                .iter()
+                .filter(|(_, profile)| token_expiry(&profile.token).is_some_and(|exp| exp <= now))
+                .map(|(name, _)| name.clone())
+                .collect();
+            if names.is_empty() {
+                info!(
+                    "No profiles with expired tokens in {}.",
+                    settings.profiles_filename
+                );
+                return Ok(());
+            }
+            names.sort();
+            println!("Profiles with expired tokens:");
+            for name in &names {
+                println!("- {} (address: {})", name, profiles.0[name].address);
+            }
+            // A dry run deletes nothing, so there is nothing to confirm.
+            if !args.force && !dry_run() {
+                let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                    .with_prompt(format!("Delete these {} profile(s)?", names.len()))
+                    .interact()?;
+                if !confirmation {
+                    println!("Prune cancelled.");
+                    return Ok(());
+                }
+            }
+
+            let active_token = read_active_token(&settings).ok().flatten();
+            let mut active_pruned = None;
+            for name in &names {
+                if let Some(profile) = profiles.0.remove(name) {
+                    if active_token.as_deref() == Some(profile.token.as_str()) {
+                        active_pruned = Some(name.clone());
+                    }
+                }
+            }
+            write_profiles(&settings, &profiles)?;
+            info!(
+                "Pruned {} profile(s) with expired tokens from {}.",
+                names.len(),
+                settings.profiles_filename
+            );
+            if let Some(name) = active_pruned {
+                eprintln!(
+                    "Warning: pruned profile '{}' is the active session. {} was left unchanged; log in again or switch to another profile.",
+                    name, settings.cli_config_filename
+                );
+            }
+        }
+        Commands::Delete(args) => {
+            let profile_name = args.profile_name.unwrap_or_default();
+            let mut profiles = read_profiles(&settings)?;
+            if !profiles.0.contains_key(&profile_name) {
+                println!(
+                    "Profile '{}' not found in {}. Nothing to delete.",
+                    profile_name, settings.profiles_filename
+                );
+                anyhow::bail!(ErrorCategory::NotFound.error("Profile not found for deletion."));
+            }
+            if let Some(replacement) = &args.switch_to {
+                if replacement == &profile_name {
+                    anyhow::bail!(ErrorCategory::Invalid.error(
+                        "--switch-to must name a different profile than the one deleted."
+                            .to_string()
+                    ));
+                }
+                if !profiles.0.contains_key(replacement) {
+                    anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                        "Profile '{}' (from --switch-to) not found in {}.",
+                        replacement, settings.profiles_filename
+                    )));
+                }
+            }
+
+            // Another profile with the same token keeps the session backed.
+            let active_token = read_active_token(&settings).ok().flatten();
+            let deleting_active = active_token.as_deref().is_some_and(|token| {
+                profiles.0[&profile_name].token == token
+                    && !profiles
+                        .0
+                        .iter()
+                        .any(|(name, profile)| name != &profile_name && profile.token == token)
+            });
+            if deleting_active {
+                println!("'{}' is the active profile.", profile_name);
+            }
+
+            if !args.force {
+                let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                    .with_prompt(format!(
+                        "Are you sure you want to delete the profile '{}'?",
+                        profile_name
+                    ))
+                    .interact()?;
+                if !confirmation {
+                    println!("Deletion cancelled.");
+                    return Ok(());
+                }
+            }
+
+            if profiles.0.remove(&profile_name).is_some() {
+                write_profiles(&settings, &profiles)?;
+                info!(
+                    "Profile '{}' deleted from {}.",
+                    profile_name, settings.profiles_filename
+                );
+            }
+            if !deleting_active {
+                return Ok(());
+            }
+
+            let replacement = match args.switch_to {
+                Some(name) => Some(name),
+                None if !args.force && std::io::stdin().is_terminal() && !profiles.0.is_empty() => {
+                    let mut names: Vec<String> = profiles.0.keys().cloned().collect();
+                    names.sort();
+                    let mut items = names.clone();
+                    items.push("(keep the current session)".to_string());
+                    let selection = Select::with_theme(prompt_theme().as_ref())
+                        .with_prompt("Switch to another profile?")
+                        .items(&items)
+                        .default(0)
+                        .interact_opt()?;
+                    selection.and_then(|index| names.get(index).cloned())
+                }
+                None => None,
+            };
+            match replacement {
+                Some(name) => activate_profile(&settings, &mut profiles, &name, "delete")?,
+                None => eprintln!(
+                    "Warning: {} still holds the token of deleted profile '{}', which no profile backs now. Run 'switch' to activate another profile.",
+                    settings.cli_config_filename, profile_name
+                ),
+            }
+        }
+        Commands::Config(args) => match args.command {
+            ConfigCommands::Path => {
+                let config_dir = get_app_config_dir()?;
+                let profiles_path = get_profiles_filepath(&settings)?;
+                let local_profiles_found =
+                    settings.local_profiles && find_local_profiles_file(&settings)?.is_some();
+                let profiles_scope = if local_profiles_found {
+                    "local"
+                } else {
+                    "global"
+                };
+                let cli_toml_paths = get_cli_toml_paths(&settings)?;
+                if json {
+                    let entry = serde_json::json!({
+                        "config_dir": config_dir,
+                        "config_file": config_dir.join(DEFAULT_CONFIG_FILENAME),
+                        "profiles_file": profiles_path,
+                        "profiles_scope": profiles_scope,
+                        "cli_toml": cli_toml_paths.first(),
+                        "cli_toml_mirrors": cli_toml_paths.get(1..),
+                    });
+                    println!("{}", serde_json::to_string_pretty(&entry)?);
+                    return Ok(());
+                }
+                println!("Config directory: {}", config_dir.display());
+                println!(
+                    "Settings file:    {}",
+                    config_dir.join(DEFAULT_CONFIG_FILENAME).display()
+                );
+                println!(
+                    "Profiles file:    {} ({})",
+                    profiles_path.display(),
+                    profiles_scope
+                );
+                if settings.local_profiles && !local_profiles_found {
+                    println!(
+                        "                  (no {}/{} found up to the repository root)",
+                        LOCAL_PROFILES_DIR, settings.profiles_filename
+                    );
+                }
+                for (index, path) in cli_toml_paths.iter().enumerate() {
+                    let label = if index == 0 {
+                        format!("{}:", settings.cli_config_filename)
+                    } else {
+                        "Mirror:".to_string()
+                    };
+                    println!("{:<18}{}", label, path.display());
+                }
+            }
+            ConfigCommands::Show => {
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&settings)?);
+                } else {
+                    print!("{}", toml::to_string_pretty(&settings)?);
+                }
+            }
+        },
+        Commands::Env(args) => match args.command.unwrap_or(EnvCommands::Current) {
+            EnvCommands::Current if porcelain => {
+                let environment = get_current_environment(&settings)?;
+                println!("{}", environment.unwrap_or_default());
+            }
+            EnvCommands::Current if json => {
+                let environment = get_current_environment(&settings)?;
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(
+                        &serde_json::json!({ "environment": environment })
+                    )?
+                );
+            }
+            EnvCommands::Current => match get_current_environment(&settings) {
+                Ok(Some(env)) => println!("Current environment: {}", env),
+                Ok(None) => println!("Environment not set."),
+                Err(e) => anyhow::bail!("Failed to get current environment: {}", e),
+            },
+            EnvCommands::List => {
+                let profiles = read_profiles(&settings)?;
+                let mut env_map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                for (name, profile) in profiles.0.iter() {
+                    env_map
+                        .entry(profile.address.clone())
+                        .or_default()
+                        .push(name.clone());
+                }
+
+                let current_env = get_current_environment(&settings)?;
+                if json {
+                    let entries: serde_json::Map<String, serde_json::Value> = env_map
+                        .into_iter()
+                        .map(|(env, mut names)| {
+                            names.sort();
+                            let current = current_env.as_ref() == Some(&env);
+                            let entry =
+                                serde_json::json!({ "profiles": names, "current": current });
+                            (env, entry)
+                        })
+                        .collect();
+                    println!("{}", serde_json::to_string_pretty(&entries)?);
+                    return Ok(());
+                }
+                if env_map.is_empty() {
+                    println!(
+                        "No environments found. Add profiles to {} first.",
+                        settings.profiles_filename
+                    );
+                } else if table {
+                    let rows: Vec<Vec<String>> = env_map
+                        .into_iter()
+                        .map(|(env, mut names)| {
+                            names.sort();
+                            let current = current_env.as_ref() == Some(&env);
+                            vec![
+                                env,
+                                names.join(", "),
+                                if current { "*" } else { "" }.to_string(),
+                            ]
+                        })
+                        .collect();
+                    println!(
+                        "{}",
+                        render_table(&["ENVIRONMENT", "PROFILES", "CURRENT"], &rows)
+                    );
+                } else {
+                    println!("Known environments:");
+                    for (env, mut names) in env_map {
+                        names.sort();
+                        let current_tag = if current_env.as_ref() == Some(&env) {
+                            " (current)"
+                        } else {
+                            ""
+                        };
+                        println!("- {}{} [profiles: {}]", env, current_tag, names.join(", "));
+                    }
+                }
+            }
+            EnvCommands::Use(use_args) => {
+                let address = resolve_address_alias(&settings, &use_args.address);
+                validate_address(&address)?;
+                let mut profiles = read_profiles(&settings)?;
+                let chosen_profile = if let Some(profile_name) = profile_scope {
+                    let profile = profiles.0.get(&profile_name).cloned().ok_or_else(|| {
+                        ErrorCategory::NotFound
+                            .error(format!("Profile '{}' not found.", profile_name))
+                    })?;
+
+                    if profile.address != address {
+                        anyhow::bail!(
+                            "Profile '{}' uses address '{}' which does not match '{}'.",
+                            profile_name,
+                            profile.address,
+                            address
+                        );
+                    }
+                    (profile_name, profile)
+                } else {
+                    let matching_profiles: Vec<(String, Profile)> = profiles
+                        .0
+                        .iter()
+                        .filter(|(_, profile)| profile.address == address)
+                        .map(|(name, profile)| (name.clone(), profile.clone()))
+                        .collect();
+
+                    match matching_profiles.len() {
+                        0 => {
+                            anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                                "No profiles found for environment '{}'. Create one before switching.",
+                                address
+                            )));
+                        }
+                        1 => matching_profiles[0].clone(),
+                        _ => {
+                            let profile_names: Vec<String> = matching_profiles
+                                .iter()
+                                .map(|(name, _)| name.clone())
+                                .collect();
+                            let selection = Select::with_theme(prompt_theme().as_ref())
+                                .with_prompt("Select a profile for this environment")
+                                .items(&profile_names)
+                                .default(0)
+                                .interact_opt()?
+                                .context("No profile selected or selection cancelled.")?;
+
+                            matching_profiles[selection].clone()
+                        }
+                    }
+                };
+
+                let (profile_name, profile) = chosen_profile;
+                activate_profile(&settings, &mut profiles, &profile_name, "env use")?;
+                info!(
+                    "Environment set to '{}' and switched to profile '{}'.",
+                    profile.address, profile_name
+                );
+            }
+            EnvCommands::Rename(rename_args) => {
+                validate_address(&rename_args.new)?;
+                let mut profiles = read_profiles(&settings)?;
+                let mut retargeted = 0;
+                for profile in profiles.0.values_mut() {
+                    if profile.address == rename_args.old {
+                        profile.set_address(rename_args.new.clone());
+                        retargeted += 1;
+                    }
+                }
+                let is_current = get_current_environment(&settings)?.as_deref()
+                    == Some(rename_args.old.as_str());
+                if retargeted == 0 && !is_current {
+                    anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                        "No profiles found for environment '{}', and it is not the current environment.",
+                        rename_args.old
+                    )));
+                }
+
+                if retargeted > 0 {
+                    write_profiles(&settings, &profiles)?;
+                }
+                info!(
+                    "Renamed environment '{}' to '{}': {} profile(s) retargeted.",
+                    rename_args.old, rename_args.new, retargeted
+                );
+
+                if is_current {
+                    let mut cli_toml = read_cli_toml(&settings)?;
+                    set_toml_value(&mut cli_toml, "default_host", &rename_args.new);
+                    sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+                    write_cli_toml(&settings, &cli_toml)?;
+                    info!(
+                        "Updated default_host in {} to '{}'.",
+                        settings.cli_config_filename, rename_args.new
+                    );
+                }
+            }
+            EnvCommands::Remove(remove_args) => {
+                let mut profiles = read_profiles(&settings)?;
+                let mut names: Vec<String> = profiles
+                    .0
+                    .iter()
+                    .filter(|(_, profile)| profile.address == remove_args.address)
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                names.sort();
+                if !names.is_empty() {
+                    if !remove_args.force {
+                        anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                            "Environment '{}' is still used by profile(s): {}. Delete them first or pass --force to delete them too.",
+                            remove_args.address,
+                            names.join(", ")
+                        )));
+                    }
+                    for name in &names {
+                        profiles.0.remove(name);
+                    }
+                    write_profiles(&settings, &profiles)?;
+                    info!(
+                        "Deleted {} profile(s) for environment '{}' from {}: {}.",
+                        names.len(),
+                        remove_args.address,
+                        settings.profiles_filename,
+                        names.join(", ")
+                    );
+                }
+
+                if !get_cli_toml_path(&settings)?.exists() {
+                    info!(
+                        "{} not found. Nothing else to remove.",
+                        settings.cli_config_filename
+                    );
+                    return Ok(());
+                }
+                let mut cli_toml = read_cli_toml(&settings)?;
+                let (removed, cleared_default) =
+                    forget_environment(&mut cli_toml, &profiles, &remove_args.address);
+                if removed.is_empty() && !cleared_default {
+                    info!(
+                        "No server_configs entries or defaults in {} point at '{}'.",
+                        settings.cli_config_filename, remove_args.address
+                    );
+                    return Ok(());
+                }
+                write_cli_toml(&settings, &cli_toml)?;
+                if !removed.is_empty() {
+                    info!("Removed server_configs entries: {}.", removed.join(", "));
+                }
+                if cleared_default {
+                    info!(
+                        "Cleared default_host/default_server, which pointed at '{}'.",
+                        remove_args.address
+                    );
+                }
+            }
+        },
+        Commands::SetAddress(SetAddressArgs {
+            all_matching: Some(addresses),
+            protocol,
+            force,
+            ..
+        }) => {
+            let [old_address, new_address] = addresses.as_slice() else {
+                anyhow::bail!("--all-matching takes an old and a new address.");
+            };
+            let new_address = resolve_address_alias(&settings, new_address);
+            let new_address = apply_address_protocol(&new_address, protocol);
+            validate_address(&new_address)?;
+            let mut profiles = read_profiles(&settings)?;
+            let mut names: Vec<String> = profiles
+                .0
+                .iter()
+                .filter(|(_, profile)| &profile.address == old_address)
+                .map(|(name, _)| name.clone())
+                .collect();
+            if names.is_empty() {
+                anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                    "No profiles found for address '{}' in {}.",
+                    old_address, settings.profiles_filename
+                )));
+            }
+            names.sort();
+            println!("Profiles using address '{}':", old_address);
+            for name in &names {
+                println!("- {}", name);
+            }
+            if !force {
+                let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                    .with_prompt(format!(
+                        "Move these {} profile(s) to '{}'?",
+                        names.len(),
+                        new_address
+                    ))
+                    .interact()?;
+                if !confirmation {
+                    println!("Set address cancelled.");
+                    return Ok(());
+                }
+            }
+
+            for name in &names {
+                if let Some(profile) = profiles.0.get_mut(name) {
+                    profile.set_address(new_address.clone());
+                }
+            }
+            write_profiles(&settings, &profiles)?;
+            info!(
+                "Updated address for {} profile(s) from '{}' to '{}'.",
+                names.len(),
+                old_address,
+                new_address
+            );
+
+            if get_cli_toml_path(&settings)?.exists() {
+                let mut cli_toml = read_cli_toml(&settings)?;
+                let active_token = cli_toml
+                    .get(&settings.cli_token_key)
+                    .and_then(|item| item.as_str())
+                    .map(|token| token.to_string());
+                let active_moved = names
+                    .iter()
+                    .any(|name| active_token.as_deref() == Some(profiles.0[name].token.as_str()));
+                let host_matches = cli_toml.get("default_host").and_then(|item| item.as_str())
+                    == Some(old_address.as_str());
+                if active_moved || host_matches {
+                    set_toml_value(&mut cli_toml, "default_host", &new_address);
+                    sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+                    write_cli_toml(&settings, &cli_toml)?;
+                    info!(
+                        "Updated default_host in {} to '{}'.",
+                        settings.cli_config_filename, new_address
+                    );
+                }
+            }
+        }
+        Commands::SetAddress(args) => {
+            let profile_name = args.profile_name.unwrap_or_default();
+            let address = resolve_address_alias(&settings, &args.address.unwrap_or_default());
+            let address = apply_address_protocol(&address, args.protocol);
+            validate_address(&address)?;
+            let mut profiles = read_profiles(&settings)?;
+            if let Some(profile) = profiles.0.get_mut(&profile_name) {
+                let previous_address = profile.address.clone();
+                let profile_token = profile.token.clone();
+                profile.set_address(address.clone());
+
+                write_profiles(&settings, &profiles)?;
+                info!(
+                    "Updated address for profile '{}' to '{}'.",
+                    profile_name, address
+                );
+
+                if let Ok(cli_toml_path) = get_cli_toml_path(&settings) {
+                    if cli_toml_path.exists() {
+                        let mut cli_toml = read_cli_toml(&settings)?;
+                        let active_token_matches = cli_toml
+                            .get(&settings.cli_token_key)
+                            .and_then(|item| item.as_str())
+                            .map(|token| token == profile_token)
+                            .unwrap_or(false);
+                        let host_matches = cli_toml
+                            .get("default_host")
+                            .and_then(|item| item.as_str())
+                            .map(|host| host == previous_address)
+                            .unwrap_or(false);
+                        if active_token_matches || host_matches {
+                            set_toml_value(&mut cli_toml, "default_host", &address);
+                            update_cli_server_target(&mut cli_toml, &profile_name, &address);
+                            sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+                            write_cli_toml(&settings, &cli_toml)?;
+                            info!(
+                                "Updated default_host in {} to '{}'.",
+                                settings.cli_config_filename, address
+                            );
+                        }
+                    }
+                }
+            } else {
+                anyhow::bail!(
+                    ErrorCategory::NotFound.error(format!("Profile '{}' not found.", profile_name))
+                );
+            }
+        }
+        Commands::Rotate(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            let target_address = if args.all {
+                None
+            } else if let Some(address) = args.address.clone() {
+                Some(address)
+            } else {
+                Some(get_current_environment(&settings)?.context(
+                    "Current environment is not set. Use --address or --all instead.",
+                )?)
+            };
+
+            let mut profile_names: Vec<String> = profiles
+                .0
+                .iter()
+                .filter(|(_, profile)| {
+                    target_address
+                        .as_ref()
+                        .is_none_or(|address| &profile.address == address)
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            profile_names.sort();
+            if profile_names.is_empty() {
+                anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                    "No profiles found in {}{}.",
+                    settings.profiles_filename,
+                    target_address
+                        .as_ref()
+                        .map(|env| format!(" for environment '{}'", env))
+                        .unwrap_or_default()
+                )));
+            }
+
+            if let Some(backup_path) = backup_profiles_file(&settings)? {
+                info!(
+                    "Backed up {} to {:?}.",
+                    settings.profiles_filename, backup_path
+                );
+            }
 
-            let profile_name_to_switch = match args.profile_name {
-                Some(name) => {
-                    if let Some(filter) = &env_filter {
-                        if let Some(profile) = profiles.0.get(&name) {
-                            if &profile.address != filter {
-                                anyhow::bail!(
-                                    "Profile '{}' uses address '{}' which does not match the requested environment '{}'.",
-                                    name,
-                                    profile.address,
-                                    filter
-                                );
-                            }
+            let active_token = read_active_token(&settings)?;
+            let mut summary = OperationSummary::default();
+            let mut rotated_active: Option<String> = None;
+            let total = profile_names.len();
+            for profile_name in profile_names {
+                let profile = profiles
+                    .0
+                    .get_mut(&profile_name)
+                    .context("Profile disappeared while rotating.")?;
+                info!(
+                    "Rotating '{}' against '{}'...",
+                    profile_name, profile.address
+                );
+                match fetch_server_issued_token(&settings, &profile.address) {
+                    Ok(token) => {
+                        if active_token.as_deref() == Some(profile.token.as_str()) {
+                            rotated_active = Some(profile_name.clone());
                         }
+                        profile.token = token;
+                        summary.record_success(&profile_name);
                     }
-                    name
-                }
-                None => {
-                    let mut filtered_profiles: HashMap<String, Profile> = profiles.0.clone();
-                    if let Some(env) = &env_filter {
-                        println!("Environment filter: {}", env);
-                        filtered_profiles.retain(|_, profile| &profile.address == env);
-                    }
-
-                    if filtered_profiles.is_empty() {
-                        println!(
-                            "No profiles found in {}{}.",
-                            settings.profiles_filename,
-                            env_filter
-                                .as_ref()
-                                .map(|env| format!(" for environment '{}'", env))
-                                .unwrap_or_default()
-                        );
-                        anyhow::bail!("No profiles available to switch.");
+                    Err(e) => {
+                        summary.record_failure(&profile_name, &e);
+                        if !args.continue_on_error {
+                            break;
+                        }
                     }
-                    let mut profile_names: Vec<String> =
-                        filtered_profiles.keys().cloned().collect();
-                    profile_names.sort();
-                    let selection = Select::with_theme(&ColorfulTheme::default())
-                        .with_prompt("Select a profile to switch to")
-                        .items(&profile_names)
-                        .default(0)
-                        .interact_opt()?
-                        .context("No profile selected or selection cancelled.")?;
-
-                    profile_names[selection].clone()
                 }
-            };
+            }
 
-            if let Some(profile_to_switch) = profiles.0.get(&profile_name_to_switch) {
+            if !summary.succeeded.is_empty() {
+                write_profiles(&settings, &profiles)?;
+            }
+            if let Some(profile_name) = rotated_active {
+                let profile = &profiles.0[&profile_name];
                 let mut cli_toml = load_or_init_cli_toml(&settings)?;
-                cli_toml[&settings.cli_token_key] =
-                    Item::Value(profile_to_switch.token.clone().into());
-                cli_toml["default_host"] = Item::Value(profile_to_switch.address.clone().into());
-                update_cli_server_target(
-                    &mut cli_toml,
-                    &profile_name_to_switch,
-                    &profile_to_switch.address,
-                );
-                sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+                set_toml_value(&mut cli_toml, &settings.cli_token_key, &profile.token);
                 write_cli_toml(&settings, &cli_toml)?;
-                println!(
-                    "Switched active profile to '{}' (from {}) in {}.",
-                    profile_name_to_switch,
-                    settings.profiles_filename,
-                    settings.cli_config_filename
+                info!(
+                    "Active profile '{}' was rotated; {} now uses the new token.",
+                    profile_name, settings.cli_config_filename
                 );
-            } else {
+            }
+
+            summary.print("Rotated");
+            let attempted = summary.succeeded.len() + summary.failed.len();
+            if attempted < total {
                 println!(
-                    "Profile '{}' not found in {}. Cannot switch.", // Renamed
-                    profile_name_to_switch,
-                    settings.profiles_filename // Renamed
+                    "Stopped after the first failure; {} profile(s) were not attempted. Use --continue-on-error to rotate the rest.",
+                    total - attempted
                 );
-                println!("Available profiles: {:?}", profiles.0.keys()); // Renamed
-                anyhow::bail!("Profile not found in profiles file for switching.");
-                // Renamed
+            }
+            if summary.has_failures() {
+                anyhow::bail!("{} profile(s) failed to rotate.", summary.failed.len());
             }
         }
-        Commands::Admin => {
-            let admin_profile_name = "admin".to_string();
-            let profiles = read_profiles(&settings)?;
-            if let Some(admin_profile) = profiles.0.get(&admin_profile_name) {
-                let mut cli_toml = load_or_init_cli_toml(&settings)?;
-                cli_toml[&settings.cli_token_key] = Item::Value(admin_profile.token.clone().into());
-                cli_toml["default_host"] = Item::Value(admin_profile.address.clone().into());
-                update_cli_server_target(
-                    &mut cli_toml,
-                    &admin_profile_name,
-                    &admin_profile.address,
+        Commands::Refresh(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            let profile = profiles.0.get(&args.profile_name).ok_or_else(|| {
+                ErrorCategory::NotFound.error(format!(
+                    "Profile '{}' not found in {}.",
+                    args.profile_name, settings.profiles_filename
+                ))
+            })?;
+            ensure_token_resolved(&args.profile_name, profile)?;
+            let refusal = if profile.login_mode == Some(LoginMode::Browser) {
+                Some("was created with a browser login")
+            } else if !supports_server_issued_login(&profile.address) {
+                Some("is on maincloud, which only supports browser login")
+            } else {
+                None
+            };
+            if let Some(reason) = refusal {
+                info!(
+                    "Profile '{}' {}, so its token cannot be re-minted here.",
+                    args.profile_name, reason
+                );
+                info!(
+                    "Log in again with: spacetime-token create <NEW_NAME> --address {} --login-mode browser",
+                    profile.address
+                );
+                anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                    "Profile '{}' cannot be refreshed.",
+                    args.profile_name
+                )));
+            }
+
+            if profile.login_mode != Some(LoginMode::ServerIssued) && !args.force {
+                // A hand-stored token may be the only credential for its identity.
+                info!(
+                    "Profile '{}' was not created via server-issued login. Refreshing replaces its token with one for a new identity on '{}'.",
+                    args.profile_name, profile.address
+                );
+                if !std::io::stdin().is_terminal() {
+                    anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                        "Refusing to refresh profile '{}' without confirmation. Use --force to refresh it.",
+                        args.profile_name
+                    )));
+                }
+                let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                    .with_prompt(format!(
+                        "Replace the token of profile '{}'?",
+                        args.profile_name
+                    ))
+                    .default(false)
+                    .interact()?;
+                if !confirmation {
+                    info!("Refresh cancelled.");
+                    return Ok(());
+                }
+            }
+
+            let old_token = profile.token.clone();
+            let address = profile.address.clone();
+            info!(
+                "Requesting a new token for '{}' from '{}'...",
+                args.profile_name, address
+            );
+            let token = fetch_server_issued_token(&settings, &address)
+                .with_context(|| format!("Failed to refresh profile '{}'.", args.profile_name))?;
+            let was_active = read_active_token(&settings)?.as_deref() == Some(old_token.as_str());
+            if let Some(backup_path) = backup_profiles_file(&settings)? {
+                info!(
+                    "Backed up {} to {:?}.",
+                    settings.profiles_filename, backup_path
                 );
-                sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+            }
+            if let Some(profile) = profiles.0.get_mut(&args.profile_name) {
+                profile.token = token.clone();
+            }
+            write_profiles(&settings, &profiles)?;
+            if was_active {
+                let mut cli_toml = load_or_init_cli_toml(&settings)?;
+                set_toml_value(&mut cli_toml, &settings.cli_token_key, token);
                 write_cli_toml(&settings, &cli_toml)?;
-                println!(
-                    "Switched active profile to ADMIN '{}' (from {}) in {}.",
-                    admin_profile_name, settings.profiles_filename, settings.cli_config_filename
+                info!(
+                    "Active profile '{}' was refreshed; {} now uses the new token.",
+                    args.profile_name, settings.cli_config_filename
+                );
+            } else {
+                info!("Profile '{}' was refreshed.", args.profile_name);
+            }
+        }
+        Commands::Backup(args) => match args.command {
+            None if args.list => {
+                let backups = list_profile_backups(&settings)?;
+                if backups.is_empty() {
+                    println!("No backups of {} found.", settings.profiles_filename);
+                } else {
+                    println!("Backups of {}:", settings.profiles_filename);
+                    for backup in backups {
+                        println!("- {}", backup.display());
+                    }
+                }
+            }
+            None => match backup_profiles_file(&settings)? {
+                Some(backup_path) => info!(
+                    "Backed up {} to {:?}.",
+                    settings.profiles_filename, backup_path
+                ),
+                None => info!(
+                    "{} is empty or missing. Nothing to back up.",
+                    settings.profiles_filename
+                ),
+            },
+            Some(BackupCommands::Restore(restore_args)) => {
+                let mut backup_path = PathBuf::from(&restore_args.file);
+                if !backup_path.exists() {
+                    backup_path = get_app_config_dir()?.join(&restore_args.file);
+                }
+                if !backup_path.exists() {
+                    println!("Available backups: {:?}", list_profile_backups(&settings)?);
+                    anyhow::bail!(ErrorCategory::NotFound
+                        .error(format!("Backup '{}' not found.", restore_args.file)));
+                }
+                let content = fs::read_to_string(&backup_path)
+                    .with_context(|| format!("Failed to read backup {:?}", backup_path))?;
+                toml::from_str::<UserProfiles>(&content).with_context(|| {
+                    format!("Backup {:?} is not a valid profiles file.", backup_path)
+                })?;
+
+                if !restore_args.force {
+                    let confirmation = Confirm::with_theme(prompt_theme().as_ref())
+                        .with_prompt(format!(
+                            "Are you sure you want to replace {} with {:?}?",
+                            settings.profiles_filename, backup_path
+                        ))
+                        .interact()?;
+                    if !confirmation {
+                        println!("Restore cancelled.");
+                        return Ok(());
+                    }
+                }
+
+                if let Some(previous_backup) = backup_profiles_file(&settings)? {
+                    info!(
+                        "Backed up current {} to {:?}.",
+                        settings.profiles_filename, previous_backup
+                    );
+                }
+                write_file_atomically(&get_profiles_filepath(&settings)?, &content)?;
+                info!(
+                    "Restored {} from {:?}.",
+                    settings.profiles_filename, backup_path
                 );
+            }
+        },
+        Commands::Copy(args) => {
+            let address = match &args.address {
+                Some(address) => {
+                    let address = resolve_address_alias(&settings, address);
+                    let address = apply_address_protocol(&address, args.protocol);
+                    validate_address(&address)?;
+                    Some(address)
+                }
+                None => None,
+            };
+            let mut profiles = read_profiles(&settings)?;
+            if profiles.0.contains_key(&args.dest) {
+                anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                    "Profile '{}' already exists in {}. Use a different name or delete the existing one first.",
+                    args.dest,
+                    settings.profiles_filename
+                )));
+            }
+            let mut profile = profiles.0.get(&args.source).cloned().ok_or_else(|| {
+                ErrorCategory::NotFound.error(format!("Profile '{}' not found.", args.source))
+            })?;
+            if let Some(address) = address {
+                profile.address = address;
+            }
+            profile.created_at = Some(now_rfc3339());
+            profile.last_used = None;
+            profiles.0.insert(args.dest.clone(), profile);
+            if args.activate {
+                activate_profile(&settings, &mut profiles, &args.dest, "copy")?;
             } else {
+                write_profiles(&settings, &profiles)?;
+            }
+            info!(
+                "Copied profile '{}' to '{}' in {}.",
+                args.source, args.dest, settings.profiles_filename
+            );
+
+            if args.activate {
+                info!(
+                    "Profile '{}' also set as active in {}.",
+                    args.dest, settings.cli_config_filename
+                );
+            }
+        }
+        Commands::Completions(args) => {
+            // Complete for whichever name the tool was invoked as (`spacetime-token` or `stt`).
+            let bin_name = std::env::args()
+                .next()
+                .as_deref()
+                .map(Path::new)
+                .and_then(|path| path.file_stem())
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(APP_DIR_NAME)
+                .to_string();
+            let mut command = Cli::command();
+            if !args.install {
+                clap_complete::generate(
+                    args.shell,
+                    &mut command,
+                    &bin_name,
+                    &mut std::io::stdout(),
+                );
+                if let Some(snippet) = profile_completion_snippet(args.shell, &bin_name) {
+                    print!("{}", snippet);
+                }
+                return Ok(());
+            }
+
+            let path = completion_install_path(args.shell, &bin_name)?;
+            if path.exists() && !args.force {
+                anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                    "{} already exists. Use --force to overwrite it.",
+                    path.display()
+                )));
+            }
+            let mut script = Vec::new();
+            clap_complete::generate(args.shell, &mut command, &bin_name, &mut script);
+            let mut script =
+                String::from_utf8(script).context("Generated completion script is not UTF-8")?;
+            if let Some(snippet) = profile_completion_snippet(args.shell, &bin_name) {
+                script.push_str(&snippet);
+            }
+            if let Some(parent_dir) = path.parent() {
+                if !dry_run() {
+                    fs::create_dir_all(parent_dir)
+                        .with_context(|| format!("Failed to create directory {:?}", parent_dir))?;
+                }
+            }
+            write_file_atomically(&path, &script)
+                .with_context(|| format!("Failed to write {:?}", path))?;
+            if !dry_run() {
                 println!(
-                    "ADMIN profile ('{}') not found in {}. Cannot switch.", // Renamed
-                    admin_profile_name,
-                    settings.profiles_filename // Renamed
+                    "Installed {} completions to {}.",
+                    args.shell,
+                    path.display()
                 );
-                println!("Ensure a profile named 'admin' exists with a valid token."); // Renamed
-                anyhow::bail!("Admin profile not found."); // Renamed
+                if args.shell == clap_complete::Shell::Zsh {
+                    println!(
+                        "Make sure {} is in your fpath before compinit, e.g. add 'fpath=(~/.zfunc $fpath)' to ~/.zshrc.",
+                        path.parent().unwrap_or(&path).display()
+                    );
+                }
+                println!("Open a new shell to use them.");
             }
         }
-        Commands::Save(args) => {
-            let cli_toml_path = get_cli_toml_path(&settings)?;
-            if !cli_toml_path.exists() {
+        Commands::CompleteProfiles => unreachable!("handled before settings are loaded"),
+        Commands::Note(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            let profile = profiles.0.get_mut(&args.profile_name).ok_or_else(|| {
+                ErrorCategory::NotFound.error(format!("Profile '{}' not found.", args.profile_name))
+            })?;
+            let text = match args.text {
+                Some(text) => text,
+                None => {
+                    let mut text = String::new();
+                    std::io::stdin()
+                        .read_to_string(&mut text)
+                        .context("Failed to read note from stdin")?;
+                    text
+                }
+            };
+
+            let text = text.trim();
+            profile.note = (!text.is_empty()).then(|| text.to_string());
+            write_profiles(&settings, &profiles)?;
+            if text.is_empty() {
+                info!("Cleared note for profile '{}'.", args.profile_name);
+            } else {
+                info!("Updated note for profile '{}'.", args.profile_name);
+            }
+        }
+        Commands::Tag(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            let profile = profiles.0.get_mut(&args.profile_name).ok_or_else(|| {
+                ErrorCategory::NotFound.error(format!("Profile '{}' not found.", args.profile_name))
+            })?;
+            for tag in &args.tags {
+                let tag = tag.trim();
+                if tag.is_empty() || tag.contains(char::is_whitespace) {
+                    anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                        "Invalid tag '{}': tags must be non-empty words.",
+                        tag
+                    )));
+                }
+                if !profile.tags.iter().any(|existing| existing == tag) {
+                    profile.tags.push(tag.to_string());
+                }
+            }
+            profile.tags.sort();
+            let tags = profile.tags.join(", ");
+            write_profiles(&settings, &profiles)?;
+            info!("Profile '{}' is tagged: {}.", args.profile_name, tags);
+        }
+        Commands::Untag(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            let profile = profiles.0.get_mut(&args.profile_name).ok_or_else(|| {
+                ErrorCategory::NotFound.error(format!("Profile '{}' not found.", args.profile_name))
+            })?;
+            for tag in &args.tags {
+                if !profile.tags.contains(tag) {
+                    println!("Profile '{}' has no tag '{}'.", args.profile_name, tag);
+                }
+            }
+            profile.tags.retain(|tag| !args.tags.contains(tag));
+            let remaining = profile.tags.len();
+            write_profiles(&settings, &profiles)?;
+            info!(
+                "Removed tag(s) from profile '{}'; {} tag(s) left.",
+                args.profile_name, remaining
+            );
+        }
+        Commands::Find(args) => {
+            let profiles = read_profiles(&settings)?;
+            let active_token = read_active_token(&settings).ok().flatten();
+            let query = args.query.to_lowercase();
+            let mut matches = profiles.0.clone();
+            matches.retain(|name, profile| {
+                name.to_lowercase().contains(&query)
+                    || (!args.name_only
+                        && (profile.address.to_lowercase().contains(&query)
+                            || profile
+                                .note
+                                .as_ref()
+                                .is_some_and(|note| note.to_lowercase().contains(&query))))
+            });
+            if matches.is_empty() {
                 anyhow::bail!(
-                    "{} does not exist. Cannot save token.",
-                    settings.cli_config_filename
+                    ErrorCategory::NotFound.error(format!("No profiles match '{}'.", args.query))
                 );
             }
-            let cli_toml = read_cli_toml(&settings)?;
 
-            let mut profiles = read_profiles(&settings)?;
-            if profiles.0.contains_key(&args.profile_name) {
-                anyhow::bail!("Profile '{}' already exists in {}. Use a different name or delete the existing one first.", args.profile_name, settings.profiles_filename);
+            let sorted_profile_names = sort_profile_names(&matches, ListSort::Name);
+            if json {
+                let entries: Vec<serde_json::Value> = sorted_profile_names
+                    .iter()
+                    .map(|name| profile_json(name, &matches[name], active_token.as_deref()))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else {
+                println!("Profiles matching '{}':", args.query);
+                let widths = profile_line_widths(&matches);
+                for name in &sorted_profile_names {
+                    println!(
+                        "{}",
+                        format_profile_line(name, &matches[name], active_token.as_deref(), widths)
+                    );
+                }
             }
+        }
+        Commands::Exec(args) => {
+            let profiles = read_profiles(&settings)?;
+            let profile = profiles.0.get(&args.profile_name).ok_or_else(|| {
+                ErrorCategory::NotFound.error(format!("Profile '{}' not found.", args.profile_name))
+            })?;
+            ensure_token_resolved(&args.profile_name, profile)?;
 
-            match (
-                cli_toml.get(&settings.cli_token_key),
-                cli_toml.get("default_host"),
-            ) {
-                (Some(token_item), Some(host_item)) => {
-                    if let (Some(token_str), Some(host_str)) =
-                        (token_item.as_str(), host_item.as_str())
-                    {
-                        let profile = Profile {
-                            token: token_str.to_string(),
-                            address: host_str.to_string(),
-                        };
-                        profiles.0.insert(args.profile_name.clone(), profile);
-                        write_profiles(&settings, &profiles)?;
-                        println!(
-                            "Saved current active session as profile '{}' in {}.",
-                            args.profile_name, settings.profiles_filename
-                        );
-                    } else {
-                        anyhow::bail!(
-                            "Token or host in {} are not strings.",
-                            settings.cli_config_filename
-                        );
+            let mut cli_toml = load_or_init_cli_toml(&settings)?;
+            let cli_toml_path = get_cli_toml_path(&settings)?;
+            let _restore_guard = CliTomlRestoreGuard::snapshot(cli_toml_path.clone())?;
+            set_toml_value(&mut cli_toml, &settings.cli_token_key, &profile.token);
+            set_toml_value(&mut cli_toml, "default_host", &profile.address);
+            update_cli_server_target(&mut cli_toml, &args.profile_name, &profile.address);
+            write_file_atomically(&cli_toml_path, &cli_toml.to_string())?;
+
+            let command_args: Vec<&str> = args.args.iter().map(String::as_str).collect();
+            let status = external_command_status(SPACETIME_CLI_COMMAND, &command_args, &args.envs)?;
+            if let Some(status) = status.filter(|status| !status.success()) {
+                // Returning drops the guard, restoring cli.toml before the exit code is passed on.
+                anyhow::bail!(AlreadyReported(child_exit_code(status)));
+            }
+        }
+        Commands::Doctor(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            let cli_toml_path = get_cli_toml_path(&settings)?;
+            let mut cli_toml = if cli_toml_path.exists() {
+                Some(read_cli_toml(&settings)?)
+            } else {
+                None
+            };
+            let mut findings = diagnose(&settings, &profiles, cli_toml.as_ref());
+            findings.extend(permission_findings(&settings)?);
+            print_findings(&findings);
+
+            if args.fix && !findings.is_empty() {
+                let paths = token_file_paths(&settings)?;
+                let loose = paths.iter().filter(|path| loose_file_mode(path).is_some());
+                for path in loose.filter(|_| settings.restrict_permissions) {
+                    restrict_file_permissions(&settings, path)?;
+                    info!("Restricted {:?} to mode 600.", path);
+                }
+
+                let mut names: Vec<String> = profiles.0.keys().cloned().collect();
+                names.sort();
+                let mut repaired = 0;
+                for name in names {
+                    let address = profiles.0[&name].address.clone();
+                    let Some((normalized, uncertain)) = normalized_address(&address) else {
+                        continue;
+                    };
+                    if uncertain && !args.yes {
+                        let confirmed = std::io::stdin().is_terminal()
+                            && Confirm::with_theme(prompt_theme().as_ref())
+                                .with_prompt(format!(
+                                    "Rewrite profile '{}' address '{}' as '{}'?",
+                                    name, address, normalized
+                                ))
+                                .interact()?;
+                        if !confirmed {
+                            info!(
+                                "Left profile '{}' address '{}' unchanged (confirm, or pass --yes, to rewrite it).",
+                                name, address
+                            );
+                            continue;
+                        }
                     }
+                    if let Some(profile) = profiles.0.get_mut(&name) {
+                        profile.address = normalized.clone();
+                    }
+                    info!(
+                        "Repaired profile '{}': address '{}' -> '{}'.",
+                        name, address, normalized
+                    );
+                    repaired += 1;
                 }
-                (Some(_), None) => {
-                    anyhow::bail!(
-                        "'default_host' not found in {}. Cannot save profile.",
-                        settings.cli_config_filename
+                if repaired > 0 {
+                    write_profiles(&settings, &profiles)?;
+                }
+                if let Some(cli_toml) = cli_toml.as_mut() {
+                    info!("Repairing {}...", settings.cli_config_filename);
+                    sync_server_configs_from_profiles(cli_toml, &profiles);
+                    let active_token = cli_toml
+                        .get(&settings.cli_token_key)
+                        .and_then(|item| item.as_str())
+                        .map(|token| token.to_string());
+                    let active_profile = active_token.and_then(|token| {
+                        profiles
+                            .0
+                            .iter()
+                            .find(|(_, profile)| profile.token == token)
+                            .map(|(name, profile)| (name.clone(), profile.address.clone()))
+                    });
+                    if let Some((name, address)) = active_profile {
+                        set_toml_value(cli_toml, "default_host", &address);
+                        update_cli_server_target(cli_toml, &name, &address);
+                    }
+                    write_cli_toml(&settings, cli_toml)?;
+
+                    findings = diagnose(&settings, &profiles, Some(cli_toml));
+                    findings.extend(permission_findings(&settings)?);
+                    println!("After repair:");
+                    print_findings(&findings);
+                }
+            }
+
+            let errors = findings
+                .iter()
+                .filter(|finding| finding.severity == Severity::Error)
+                .count();
+            if errors > 0 {
+                anyhow::bail!("Doctor found {} error(s).", errors);
+            }
+        }
+        Commands::Alias(args) => match args.command {
+            AliasCommands::Add(add_args) => {
+                validate_alias_name(&add_args.name)?;
+                validate_address(&add_args.url)?;
+                let previous = settings
+                    .address_aliases
+                    .insert(add_args.name.clone(), add_args.url.clone());
+                write_app_settings(&settings)?;
+                match previous {
+                    Some(old) if old != add_args.url => info!(
+                        "Alias '{}' now points to '{}' (was '{}').",
+                        add_args.name, add_args.url, old
+                    ),
+                    _ => info!("Alias '{}' points to '{}'.", add_args.name, add_args.url),
+                }
+            }
+            AliasCommands::List => {
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&settings.address_aliases)?
+                    );
+                } else if settings.address_aliases.is_empty() {
+                    println!(
+                        "No address aliases configured. Add one with 'alias add <NAME> <URL>'."
                     );
+                } else {
+                    let width = settings
+                        .address_aliases
+                        .keys()
+                        .map(|name| name.chars().count())
+                        .max()
+                        .unwrap_or(0);
+                    for (name, url) in &settings.address_aliases {
+                        println!("{:<width$}  {}", name, url, width = width);
+                    }
+                }
+            }
+            AliasCommands::Remove(remove_args) => {
+                if settings.address_aliases.remove(&remove_args.name).is_none() {
+                    anyhow::bail!(ErrorCategory::NotFound
+                        .error(format!("Alias '{}' not found.", remove_args.name)));
                 }
-                (None, _) => {
+                write_app_settings(&settings)?;
+                info!("Alias '{}' removed.", remove_args.name);
+            }
+        },
+        Commands::SetDefault(args) => match args.profile_name {
+            Some(name) => {
+                let profiles = read_profiles(&settings)?;
+                if !profiles.0.contains_key(&name) {
                     anyhow::bail!(
-                        "User is not logged in. Token key '{}' not found in {}.",
-                        settings.cli_token_key,
-                        settings.cli_config_filename
+                        ErrorCategory::NotFound.error(format!("Profile '{}' not found.", name))
                     );
                 }
+                settings.default_profile = Some(name.clone());
+                write_app_settings(&settings)?;
+                info!("Default profile set to '{}'.", name);
+            }
+            None => {
+                settings.default_profile = None;
+                write_app_settings(&settings)?;
+                info!("Default profile cleared.");
+            }
+        },
+        Commands::Edit => {
+            let profiles_path = get_profiles_filepath(&settings)?;
+            if !profiles_path.exists() {
+                read_profiles(&settings)?;
             }
+            let editor = std::env::var("EDITOR")
+                .ok()
+                .filter(|editor| !editor.trim().is_empty())
+                .unwrap_or_else(|| {
+                    if cfg!(windows) {
+                        "notepad".to_string()
+                    } else {
+                        "vi".to_string()
+                    }
+                });
+            // EDITOR may carry arguments, e.g. "code --wait"
+            let mut editor_parts = editor.split_whitespace();
+            let editor_command = editor_parts.next().unwrap_or("vi");
+            let path_arg = profiles_path.to_string_lossy();
+            let mut editor_args: Vec<&str> = editor_parts.collect();
+            editor_args.push(&path_arg);
+            run_external_command(editor_command, &editor_args, &[])?;
+
+            let profiles = read_profiles(&settings).with_context(|| {
+                format!(
+                    "{} is invalid after editing. Your changes were kept; run 'edit' again to fix them.",
+                    settings.profiles_filename
+                )
+            })?;
+            let mut cli_toml = load_or_init_cli_toml(&settings)?;
+            let counts = sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+            if counts.added > 0 || counts.updated > 0 {
+                write_cli_toml(&settings, &cli_toml)?;
+            }
+            info!(
+                "{} is valid ({} profile(s)). server_configs: {} added, {} updated.",
+                settings.profiles_filename,
+                profiles.0.len(),
+                counts.added,
+                counts.updated
+            );
         }
-        Commands::Reset(args) => {
-            if !args.force {
-                let confirmation = dialoguer::Confirm::new()
-                    .with_prompt(format!(
-                        "Are you sure you want to reset {}? This will delete all profiles.",
-                        settings.profiles_filename
-                    ))
-                    .interact()?;
-                if !confirmation {
-                    println!("Reset cancelled.");
-                    return Ok(());
+        Commands::Undo => {
+            let snapshot_path = cli_toml_snapshot_path(&settings)?;
+            if !snapshot_path.exists() {
+                anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                    "No previous {} to restore.",
+                    settings.cli_config_filename
+                )));
+            }
+            let previous = fs::read_to_string(&snapshot_path)
+                .with_context(|| format!("Failed to read {:?}", snapshot_path))?
+                .parse::<DocumentMut>()
+                .with_context(|| format!("Failed to parse {:?}", snapshot_path))?;
+            // Writing snapshots the current file, so a second `undo` reverts this one.
+            write_cli_toml(&settings, &previous)?;
+
+            let profiles = read_profiles(&settings)?;
+            let token = previous
+                .get(&settings.cli_token_key)
+                .and_then(|item| item.as_str());
+            let active = token.and_then(|token| {
+                profiles
+                    .0
+                    .iter()
+                    .find(|(_, profile)| profile.token == token)
+            });
+            match (token, active) {
+                (_, Some((name, profile))) => {
+                    let address = previous
+                        .get("default_host")
+                        .and_then(|item| item.as_str())
+                        .unwrap_or(&profile.address);
+                    if let Err(e) = append_audit_log(&settings, "undo", name, address) {
+                        eprintln!("Warning: failed to write {}: {:#}", AUDIT_LOG_FILENAME, e);
+                    }
+                    info!(
+                        "Restored the previous {}. Profile '{}' is active again.",
+                        settings.cli_config_filename, name
+                    );
                 }
+                (Some(_), None) => info!(
+                    "Restored the previous {}. Its active token does not match any profile in {}.",
+                    settings.cli_config_filename, settings.profiles_filename
+                ),
+                (None, None) => info!(
+                    "Restored the previous {}. No token is active.",
+                    settings.cli_config_filename
+                ),
+            }
+        }
+        Commands::Diff(args) => {
+            let profiles = read_profiles(&settings)?;
+            let Some(profile) = profiles.0.get(&args.profile_name) else {
+                anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                    "Profile '{}' not found in {}.",
+                    args.profile_name, settings.profiles_filename
+                )));
+            };
+            ensure_token_resolved(&args.profile_name, profile)?;
+            let current = load_or_init_cli_toml(&settings)?;
+            let mut proposed = current.clone();
+            apply_profile_to_cli_toml(
+                &settings,
+                &mut proposed,
+                &profiles,
+                &args.profile_name,
+                &profile.address,
+            );
+            let changes = cli_toml_changes(
+                &current,
+                &proposed,
+                &settings.cli_token_key,
+                settings.mask_reveal,
+            );
+            if json {
+                let entries: Vec<serde_json::Value> = changes
+                    .iter()
+                    .map(|change| {
+                        serde_json::json!({
+                            "field": change.field,
+                            "current": change.current,
+                            "proposed": change.proposed,
+                        })
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+                return Ok(());
+            }
+            if changes.is_empty() {
+                println!(
+                    "Activating '{}' would not change {}.",
+                    args.profile_name, settings.cli_config_filename
+                );
+                return Ok(());
+            }
+            let unset = || "(unset)".to_string();
+            if table {
+                let rows: Vec<Vec<String>> = changes
+                    .into_iter()
+                    .map(|change| {
+                        vec![
+                            change.field,
+                            change.current.unwrap_or_else(unset),
+                            change.proposed.unwrap_or_else(unset),
+                        ]
+                    })
+                    .collect();
+                println!("{}", render_table(&["FIELD", "CURRENT", "PROPOSED"], &rows));
+                return Ok(());
             }
-            let profiles = UserProfiles::default();
-            write_profiles(&settings, &profiles)?;
-            println!("{} has been reset.", settings.profiles_filename);
-        }
-        Commands::Create(args) => {
-            let mut profiles = read_profiles(&settings)?; // Renamed
-            if profiles.0.contains_key(&args.profile_name) {
-                // Renamed
-                anyhow::bail!(
-                    "Profile '{}' already exists in {}. Cannot create.", // Renamed
-                    args.profile_name,                                   // Renamed
-                    settings.profiles_filename                           // Renamed
+            println!(
+                "Activating '{}' would change {}:",
+                args.profile_name, settings.cli_config_filename
+            );
+            for change in changes {
+                println!(
+                    "  {}: {} -> {}",
+                    change.field,
+                    change.current.unwrap_or_else(unset),
+                    change.proposed.unwrap_or_else(unset)
                 );
             }
-
-            run_external_command(SPACETIME_CLI_COMMAND, &["logout"])
-                .context("Failed to logout from SpacetimeDB CLI.")?;
-
-            let address = args.address.unwrap_or_else(|| "local".to_string());
-            let token = if address == "local" {
+        }
+        Commands::Log(args) => {
+            if !settings.audit_log {
                 println!(
-                    "Please follow the prompts from 'spacetime login --server-issued-login {}'",
-                    address
+                    "Audit logging is disabled (audit_log = false in {}).",
+                    DEFAULT_CONFIG_FILENAME
                 );
-                run_external_command(
-                    SPACETIME_CLI_COMMAND,
-                    &["login", "--server-issued-login", &address],
-                )
-                .with_context(|| {
-                    format!(
-                        "Failed during 'spacetime login --server-issued-login {}'",
-                        address
-                    )
-                })?;
-
-                let cli_toml_path = get_cli_toml_path(&settings)?;
-                if !cli_toml_path.exists() {
-                    anyhow::bail!(
-                        "{} does not exist after login. Cannot save token.",
-                        settings.cli_config_filename
-                    );
+            }
+            let path = get_app_config_dir()?.join(AUDIT_LOG_FILENAME);
+            if !path.exists() {
+                println!("No profile activations recorded yet.");
+                return Ok(());
+            }
+            let content =
+                fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+            let entries: Vec<&str> = content.lines().collect();
+            for entry in &entries[entries.len().saturating_sub(args.lines)..] {
+                println!("{}", entry);
+            }
+        }
+        Commands::Show(args) => {
+            let profile_name = match (args.profile_name, profile_scope) {
+                (Some(name), Some(scope)) if name != scope => {
+                    anyhow::bail!(ErrorCategory::Invalid.error(format!(
+                        "Profile '{}' and --profile '{}' disagree. Pass only one.",
+                        name, scope
+                    )));
+                }
+                (Some(name), _) | (None, Some(name)) => name,
+                (None, None) => {
+                    anyhow::bail!(ErrorCategory::Invalid.error("Pass a profile name or --profile."));
                 }
-                let cli_toml = read_cli_toml(&settings)?;
-                let token_item = cli_toml.get(&settings.cli_token_key).ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "Token key '{}' not found in {} after login.",
-                        settings.cli_token_key,
-                        settings.cli_config_filename
-                    )
-                })?;
-                token_item
-                    .as_str()
-                    .map(|value| value.to_string())
-                    .ok_or_else(|| {
-                        anyhow::anyhow!(
-                            "Token key '{}' in {} is not a string after login.",
-                            settings.cli_token_key,
-                            settings.cli_config_filename
-                        )
-                    })?
-            } else {
-                fetch_server_issued_token(&address)?
             };
-
-            let new_profile = Profile {
-                token: token.clone(),
-                address: address.clone(),
+            let profiles = read_profiles(&settings)?;
+            let Some(profile) = profiles.0.get(&profile_name) else {
+                println!(
+                    "Profile '{}' not found in {}.",
+                    profile_name, settings.profiles_filename
+                );
+                println!("Available profiles: {:?}", profiles.0.keys());
+                anyhow::bail!(ErrorCategory::NotFound.error("Profile not found in profiles file."));
             };
-            profiles.0.insert(args.profile_name.clone(), new_profile);
-            write_profiles(&settings, &profiles)?;
+            let active_token = read_active_token(&settings).ok().flatten();
+            let expiry = token_expiry(&profile.token);
+            let token = if args.full {
+                ensure_token_resolved(&profile_name, profile)?;
+                profile.token.clone()
+            } else {
+                mask_token(&profile.token, settings.mask_reveal)
+            };
+            if json {
+                let mut entry = profile_json(&profile_name, profile, active_token.as_deref());
+                let token_key = if args.full { "token" } else { "token_masked" };
+                entry[token_key] = token.into();
+                entry["expiry"] = expiry
+                    .map(|exp| exp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                    .into();
+                entry["previous_addresses"] = profile.previous_addresses.clone().into();
+                println!("{}", serde_json::to_string_pretty(&entry)?);
+                return Ok(());
+            }
 
-            let mut cli_toml = load_or_init_cli_toml(&settings)?;
-            cli_toml[&settings.cli_token_key] = Item::Value(token.into());
-            cli_toml["default_host"] = Item::Value(address.clone().into());
-            update_cli_server_target(&mut cli_toml, &args.profile_name, &address);
-            sync_server_configs_from_profiles(&mut cli_toml, &profiles);
-            write_cli_toml(&settings, &cli_toml)?;
+            if table {
+                let row = profile_table_row(&profile_name, profile, active_token.as_deref());
+                println!("{}", render_table(PROFILE_TABLE_HEADERS, &[row]));
+                return Ok(());
+            }
 
+            let not_set = || "not set".to_string();
+            let expiry = expiry_label(&profile.token);
+            let active = active_token.as_deref() == Some(profile.token.as_str());
+            println!("Profile:   {}", profile_name);
+            println!("Address:   {}", profile.address);
+            println!("Token:     {}", token);
+            println!("Expires:   {}", expiry);
+            println!("Active:    {}", if active { "yes" } else { "no" });
             println!(
-                "Successfully created and saved profile '{}' in {}.",
-                args.profile_name, settings.profiles_filename
+                "Note:      {}",
+                profile.note.clone().unwrap_or_else(not_set)
             );
+            if !profile.tags.is_empty() {
+                println!("Tags:      {}", profile.tags.join(", "));
+            }
+            println!(
+                "Created:   {}",
+                profile.created_at.clone().unwrap_or_else(not_set)
+            );
+            println!(
+                "Last used: {}",
+                profile
+                    .last_used
+                    .clone()
+                    .unwrap_or_else(|| "never".to_string())
+            );
+            if !profile.previous_addresses.is_empty() {
+                println!("Previous:  {}", profile.previous_addresses.join(", "));
+            }
         }
-        Commands::List(args) => {
+        Commands::Export(args) => {
+            let mut profiles = read_profiles(&settings)?;
+            if let Some(name) = &profile_scope {
+                let profile = profiles.0.remove(name).ok_or_else(|| {
+                    ErrorCategory::NotFound.error(format!("Profile '{}' not found.", name))
+                })?;
+                profiles.0 = HashMap::from([(name.clone(), profile)]);
+            }
+            match args.format {
+                ExportFormat::Toml => print!("{}", toml::to_string_pretty(&profiles)?),
+                ExportFormat::Json => println!("{}", serde_json::to_string_pretty(&profiles)?),
+                ExportFormat::Env | ExportFormat::Sh => {
+                    let (name, profile) = profiles
+                        .0
+                        .iter()
+                        .next()
+                        .filter(|_| profile_scope.is_some())
+                        .context("--format env and sh need a single profile via --profile.")?;
+                    ensure_token_resolved(name, profile)?;
+                    let prefix = if args.format == ExportFormat::Sh {
+                        "export "
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "{}SPACETIMEDB_TOKEN={}",
+                        prefix,
+                        shell_quote(&profile.token)
+                    );
+                    println!(
+                        "{}SPACETIMEDB_HOST={}",
+                        prefix,
+                        shell_quote(&normalize_identity_base(&profile.address))
+                    );
+                }
+            }
+        }
+        Commands::CopyToken => {
+            let profiles = read_profiles(&settings)?;
+            let (token, source) = match profile_scope {
+                Some(name) => {
+                    let profile = profiles.0.get(&name).ok_or_else(|| {
+                        ErrorCategory::NotFound.error(format!("Profile '{}' not found.", name))
+                    })?;
+                    ensure_token_resolved(&name, profile)?;
+                    (profile.token.clone(), format!("profile '{}'", name))
+                }
+                None => {
+                    let token = read_active_token(&settings)?.with_context(|| {
+                        format!("No active token found in {}.", settings.cli_config_filename)
+                    })?;
+                    let source = match profiles.0.iter().find(|(_, p)| p.token == token) {
+                        Some((name, _)) => format!("active profile '{}'", name),
+                        None => "active session".to_string(),
+                    };
+                    (token, source)
+                }
+            };
+            arboard::Clipboard::new()
+                .and_then(|mut clipboard| clipboard.set_text(token))
+                .context("Failed to copy the token to the clipboard")?;
+            println!("Copied the token of the {} to the clipboard.", source);
+        }
+        Commands::Verify(args) => {
+            if let Some(timeout) = args.timeout {
+                settings.http_timeout_secs = timeout;
+            }
             let profiles = read_profiles(&settings)?;
-            let mut active_token_opt: Option<String> = None;
             let current_env = if args.env {
                 get_current_environment(&settings).context("Failed to get current environment.")?
             } else {
                 None
             };
-
-            if let Ok(cli_toml_path) = get_cli_toml_path(&settings) {
-                if cli_toml_path.exists() {
-                    if let Ok(cli_toml_doc) = read_cli_toml(&settings) {
-                        if let Some(token_item) = cli_toml_doc.get(&settings.cli_token_key) {
-                            if let Some(token_str) = token_item.as_str() {
-                                active_token_opt = Some(token_str.to_string());
-                            }
-                        }
-                    }
-                }
+            let env = current_env.as_ref().or(args.address.as_ref());
+            let mut names: Vec<&String> = profiles
+                .0
+                .iter()
+                .filter(|(_, profile)| env.is_none_or(|env| &profile.address == env))
+                .map(|(name, _)| name)
+                .collect();
+            if names.is_empty() {
+                anyhow::bail!(ErrorCategory::NotFound.error(format!(
+                    "No profiles to verify in {}{}.",
+                    settings.profiles_filename,
+                    env.map(|env| format!(" for environment '{}'", env))
+                        .unwrap_or_default()
+                )));
             }
+            names.sort();
 
-            let mut profiles_to_display = profiles.0.clone();
-            if let Some(env) = &current_env {
-                println!("Current environment: {}", env);
-                profiles_to_display.retain(|_, profile| &profile.address == env);
+            let mut results = Vec::new();
+            for name in names {
+                let profile = &profiles.0[name];
+                info!("Verifying '{}' at '{}'...", name, profile.address);
+                let result = ensure_token_resolved(name, profile).and_then(|()| {
+                    verify_token_identity(&settings, &profile.address, &profile.token)
+                });
+                let status = VerifyStatus::from_result(&result);
+                let detail = match result {
+                    Ok(identity) => format!("identity {}", identity),
+                    Err(error) => format!("{:#}", error),
+                };
+                results.push((name, profile, status, detail));
             }
+            let count = |status| results.iter().filter(|r| r.2 == status).count();
+            let (ok, rejected, unreachable, errors) = (
+                count(VerifyStatus::Ok),
+                count(VerifyStatus::Rejected),
+                count(VerifyStatus::Unreachable),
+                count(VerifyStatus::Error),
+            );
 
-            if profiles_to_display.is_empty() {
-                println!("No profiles found in {}.", settings.profiles_filename);
-            } else {
-                println!("Available profiles in {}:", settings.profiles_filename);
-                let mut sorted_profile_names: Vec<_> = profiles_to_display.keys().collect();
-                sorted_profile_names.sort();
-
-                for profile_name in sorted_profile_names {
-                    if let Some(profile) = profiles_to_display.get(profile_name) {
-                        let mut display_name =
-                            format!("- {} (address: {})", profile_name, profile.address);
-                        if let Some(ref active_token) = active_token_opt {
-                            if &profile.token == active_token {
-                                display_name.push_str(" (current)");
-                            }
-                        }
-                        println!("{}", display_name);
-                    }
-                }
-            }
-        }
-        Commands::Current => {
-            let cli_toml_path = get_cli_toml_path(&settings)?;
-            if !cli_toml_path.exists() {
+            if json {
+                let entries: Vec<serde_json::Value> = results
+                    .iter()
+                    .map(|(name, profile, status, detail)| {
+                        serde_json::json!({
+                            "name": name,
+                            "address": profile.address,
+                            "status": status.as_str(),
+                            "detail": detail,
+                        })
+                    })
+                    .collect();
+                let summary = serde_json::json!({
+                    "profiles": entries,
+                    "ok": ok,
+                    "rejected": rejected,
+                    "unreachable": unreachable,
+                    "errors": errors,
+                });
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else if table {
+                let rows: Vec<Vec<String>> = results
+                    .iter()
+                    .map(|(name, profile, status, detail)| {
+                        vec![
+                            name.to_string(),
+                            profile.address.clone(),
+                            status.as_str().to_uppercase(),
+                            detail.clone(),
+                        ]
+                    })
+                    .collect();
                 println!(
-                    "{} not found. No active token set.",
-                    settings.cli_config_filename
+                    "{}",
+                    render_table(&["NAME", "ADDRESS", "STATUS", "DETAIL"], &rows)
                 );
-                return Ok(());
-            }
-            let cli_toml_doc = read_cli_toml(&settings)?;
-            if let Some(token_item) = cli_toml_doc.get(&settings.cli_token_key) {
-                if let Some(active_token_str) = token_item.as_str() {
-                    let profiles = read_profiles(&settings)?;
-                    let mut current_profile: Option<(String, Profile)> = None;
-                    for (profile_name, profile) in profiles.0.iter() {
-                        if profile.token == active_token_str {
-                            current_profile = Some((profile_name.clone(), profile.clone()));
-                            break;
-                        }
-                    }
-
-                    if let Some((name, profile)) = current_profile {
-                        println!("Current active profile: {}", name);
-                        println!("Address: {}", profile.address);
-                    } else {
-                        println!(
-                            "Current active token is set, but not found under any profile name in {}.", // Renamed
-                            settings.profiles_filename // Renamed
-                        );
-                    }
-                    println!("Active token: {}", mask_token(active_token_str));
-                } else {
+            } else {
+                for (name, profile, status, detail) in &results {
                     println!(
-                        "Active token key '{}' in {} is not a string.",
-                        settings.cli_token_key, settings.cli_config_filename
+                        "- {} (address: {}): {} ({})",
+                        name,
+                        profile.address,
+                        status.as_str().to_uppercase(),
+                        detail
                     );
                 }
-            } else {
-                println!(
-                    "No active token (key '{}') found in {}.",
-                    settings.cli_token_key, settings.cli_config_filename
-                );
             }
-        }
-        Commands::Delete(args) => {
-            let mut profiles = read_profiles(&settings)?;
-            if !profiles.0.contains_key(&args.profile_name) {
+            if !json {
+                println!();
                 println!(
-                    "Profile '{}' not found in {}. Nothing to delete.",
-                    args.profile_name, settings.profiles_filename
+                    "{} ok, {} rejected, {} unreachable, {} errors.",
+                    ok, rejected, unreachable, errors
                 );
-                anyhow::bail!("Profile not found for deletion.");
             }
-
-            if !args.force {
-                let confirmation = dialoguer::Confirm::new()
-                    .with_prompt(format!(
-                        "Are you sure you want to delete the profile '{}'?",
-                        args.profile_name
-                    ))
-                    .interact()?;
-                if !confirmation {
-                    println!("Deletion cancelled.");
-                    return Ok(());
+            if rejected + errors > 0 {
+                if json {
+                    // The summary above already lists the failures; a second
+                    // JSON document would break consumers.
+                    anyhow::bail!(AlreadyReported(ErrorCategory::General.exit_code()));
                 }
-            }
-
-            if profiles.0.remove(&args.profile_name).is_some() {
-                write_profiles(&settings, &profiles)?;
-                println!(
-                    "Profile '{}' deleted from {}.",
-                    args.profile_name, settings.profiles_filename
+                anyhow::bail!(
+                    "{} profile(s) have a token the server rejected and {} could not be checked.",
+                    rejected,
+                    errors
                 );
             }
         }
-        Commands::Env(args) => match args.command.unwrap_or(EnvCommands::Current) {
-            EnvCommands::Current => match get_current_environment(&settings) {
-                Ok(Some(env)) => println!("Current environment: {}", env),
-                Ok(None) => println!("Environment not set."),
-                Err(e) => anyhow::bail!("Failed to get current environment: {}", e),
-            },
-            EnvCommands::List => {
-                let profiles = read_profiles(&settings)?;
-                let mut env_map: BTreeMap<String, Vec<String>> = BTreeMap::new();
-                for (name, profile) in profiles.0.iter() {
-                    env_map
-                        .entry(profile.address.clone())
-                        .or_default()
-                        .push(name.clone());
-                }
-
-                let current_env = get_current_environment(&settings)?;
-                if env_map.is_empty() {
-                    println!(
-                        "No environments found. Add profiles to {} first.",
-                        settings.profiles_filename
-                    );
-                } else {
-                    println!("Known environments:");
-                    for (env, mut names) in env_map {
-                        names.sort();
-                        let current_tag = if current_env.as_ref() == Some(&env) {
-                            " (current)"
-                        } else {
-                            ""
-                        };
-                        println!("- {}{} [profiles: {}]", env, current_tag, names.join(", "));
-                    }
+        Commands::Ping(args) => {
+            let profiles = read_profiles(&settings)?;
+            let (label, address) = match args.profile_name.or(profile_scope) {
+                Some(name) => {
+                    let profile = profiles.0.get(&name).ok_or_else(|| {
+                        ErrorCategory::NotFound.error(format!("Profile '{}' not found.", name))
+                    })?;
+                    (format!("profile '{}'", name), profile.address.clone())
                 }
-            }
-            EnvCommands::Use(use_args) => {
-                let profiles = read_profiles(&settings)?;
-                let chosen_profile = if let Some(profile_name) = use_args.profile.clone() {
-                    let profile = profiles
-                        .0
-                        .get(&profile_name)
-                        .cloned()
-                        .context(format!("Profile '{}' not found.", profile_name))?;
-
-                    if profile.address != use_args.address {
-                        anyhow::bail!(
-                            "Profile '{}' uses address '{}' which does not match '{}'.",
-                            profile_name,
-                            profile.address,
-                            use_args.address
-                        );
-                    }
-                    (profile_name, profile)
-                } else {
-                    let matching_profiles: Vec<(String, Profile)> = profiles
-                        .0
-                        .iter()
-                        .filter(|(_, profile)| profile.address == use_args.address)
-                        .map(|(name, profile)| (name.clone(), profile.clone()))
-                        .collect();
-
-                    match matching_profiles.len() {
-                        0 => {
-                            anyhow::bail!(
-                                "No profiles found for environment '{}'. Create one before switching.",
-                                use_args.address
-                            );
+                None => {
+                    let active_token = read_active_token(&settings)?;
+                    let active = active_token.as_deref().and_then(|token| {
+                        profiles
+                            .0
+                            .iter()
+                            .find(|(_, profile)| profile.token == token)
+                    });
+                    match active {
+                        Some((name, profile)) => {
+                            (format!("profile '{}'", name), profile.address.clone())
                         }
-                        1 => matching_profiles[0].clone(),
-                        _ => {
-                            let profile_names: Vec<String> = matching_profiles
-                                .iter()
-                                .map(|(name, _)| name.clone())
-                                .collect();
-                            let selection = Select::with_theme(&ColorfulTheme::default())
-                                .with_prompt("Select a profile for this environment")
-                                .items(&profile_names)
-                                .default(0)
-                                .interact_opt()?
-                                .context("No profile selected or selection cancelled.")?;
-
-                            matching_profiles[selection].clone()
+                        None => {
+                            let env = get_current_environment(&settings)?.context(
+                                "No active profile or environment. Pass a profile name to ping.",
+                            )?;
+                            ("the current environment".to_string(), env)
                         }
                     }
-                };
+                }
+            };
 
-                let mut cli_toml = load_or_init_cli_toml(&settings)?;
-                let (profile_name, profile) = chosen_profile;
-                cli_toml["default_host"] = Item::Value(profile.address.clone().into());
-                cli_toml[&settings.cli_token_key] = Item::Value(profile.token.clone().into());
-                update_cli_server_target(&mut cli_toml, &profile_name, &profile.address);
-                sync_server_configs_from_profiles(&mut cli_toml, &profiles);
-                write_cli_toml(&settings, &cli_toml)?;
+            let (status, latency) = ping_server(&settings, &address)?;
+            if !status.is_success() {
+                anyhow::bail!(
+                    "Server at '{}' responded with status {} to ping.",
+                    address,
+                    status
+                );
+            }
+            if json {
+                let entry = serde_json::json!({
+                    "address": address,
+                    "reachable": true,
+                    "latency_ms": latency.as_millis() as u64,
+                });
+                println!("{}", serde_json::to_string_pretty(&entry)?);
+            } else {
+                println!(
+                    "Server for {} at '{}' is reachable ({} ms).",
+                    label,
+                    address,
+                    latency.as_millis()
+                );
+            }
+        }
+        Commands::Migrate(args) => {
+            let profiles_path = get_profiles_filepath(&settings)?;
+            let content = if profiles_path.exists() {
+                fs::read_to_string(&profiles_path).with_context(|| {
+                    format!("Failed to read profiles file at {:?}", profiles_path)
+                })?
+            } else {
+                String::new()
+            };
+            if content.trim().is_empty() {
                 println!(
-                    "Environment set to '{}' and switched to profile '{}'.",
-                    profile.address, profile_name
+                    "{} is empty or missing. Nothing to migrate.",
+                    settings.profiles_filename
                 );
+                return Ok(());
             }
-        },
-        Commands::SetAddress(args) => {
-            let mut profiles = read_profiles(&settings)?;
-            if let Some(profile) = profiles.0.get_mut(&args.profile_name) {
-                let previous_address = profile.address.clone();
-                let profile_token = profile.token.clone();
-                profile.address = args.address.clone();
-                let _ = profile;
-
-                write_profiles(&settings, &profiles)?;
+            if toml::from_str::<UserProfiles>(&content).is_ok() {
                 println!(
-                    "Updated address for profile '{}' to '{}'.",
-                    args.profile_name, args.address
+                    "{} is already in the new format. Nothing to migrate.",
+                    settings.profiles_filename
                 );
+                return Ok(());
+            }
 
-                if let Ok(cli_toml_path) = get_cli_toml_path(&settings) {
-                    if cli_toml_path.exists() {
-                        let mut cli_toml = read_cli_toml(&settings)?;
-                        let active_token_matches = cli_toml
-                            .get(&settings.cli_token_key)
-                            .and_then(|item| item.as_str())
-                            .map(|token| token == profile_token)
-                            .unwrap_or(false);
-                        let host_matches = cli_toml
-                            .get("default_host")
-                            .and_then(|item| item.as_str())
-                            .map(|host| host == previous_address)
-                            .unwrap_or(false);
-                        if active_token_matches || host_matches {
-                            cli_toml["default_host"] = Item::Value(args.address.clone().into());
-                            update_cli_server_target(&mut cli_toml, &args.profile_name, &args.address);
-                            sync_server_configs_from_profiles(&mut cli_toml, &profiles);
-                            write_cli_toml(&settings, &cli_toml)?;
-                            println!(
-                                "Updated default_host in {} to '{}'.",
-                                settings.cli_config_filename, args.address
-                            );
-                        }
-                    }
-                }
+            let profiles =
+                migrate_old_profiles(&content, &args.default_address).with_context(|| {
+                    format!(
+                        "{} is neither in the new nor the old format.",
+                        settings.profiles_filename
+                    )
+                })?;
+            let backup_path = backup_profiles_file(&settings)?;
+            write_profiles(&settings, &profiles)?;
+            println!(
+                "Migrated {} profile(s) to the new format with address '{}'.",
+                profiles.0.len(),
+                args.default_address
+            );
+            if let Some(backup_path) = backup_path {
+                println!("The original file was backed up to {:?}.", backup_path);
+            }
+        }
+        Commands::Status => {
+            let profiles = read_profiles(&settings)?;
+            let environment = get_current_environment(&settings).ok().flatten();
+            let active_token = read_active_token(&settings).ok().flatten();
+            let active_profile = active_token.as_deref().map(|token| {
+                profiles
+                    .0
+                    .iter()
+                    .find(|(_, profile)| profile.token == token)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_else(|| "unknown".to_string())
+            });
+            let expiry = active_token.as_deref().map(expiry_label);
+            let environments: std::collections::BTreeSet<&str> = profiles
+                .0
+                .values()
+                .map(|profile| profile.address.as_str())
+                .collect();
+            let token_masked = active_token
+                .as_deref()
+                .map(|t| mask_token(t, settings.mask_reveal));
+            if json {
+                let entry = serde_json::json!({
+                    "environment": environment,
+                    "profile": active_profile,
+                    "token_masked": token_masked,
+                    "expiry": active_token
+                        .as_deref()
+                        .and_then(token_expiry)
+                        .map(|exp| exp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+                    "profiles": profiles.0.len(),
+                    "environments": environments.len(),
+                });
+                println!("{}", serde_json::to_string_pretty(&entry)?);
+                return Ok(());
+            }
+
+            let not_set = || "not set".to_string();
+            if table {
+                let row = vec![
+                    environment.unwrap_or_else(not_set),
+                    active_profile.unwrap_or_else(not_set),
+                    token_masked.unwrap_or_else(not_set),
+                    expiry.unwrap_or_else(not_set),
+                    profiles.0.len().to_string(),
+                ];
+                println!(
+                    "{}",
+                    render_table(
+                        &["ENVIRONMENT", "PROFILE", "TOKEN", "EXPIRY", "PROFILES"],
+                        &[row]
+                    )
+                );
+                return Ok(());
+            }
+            println!("Environment:    {}", environment.unwrap_or_else(not_set));
+            println!("Active profile: {}", active_profile.unwrap_or_else(not_set));
+            println!("Token:          {}", token_masked.unwrap_or_else(not_set));
+            println!("Expires:        {}", expiry.unwrap_or_else(not_set));
+            println!(
+                "Profiles:       {} across {} environment(s)",
+                profiles.0.len(),
+                environments.len()
+            );
+        }
+        Commands::Sync(args) => {
+            let profiles = read_profiles(&settings)?;
+            let mut cli_toml = load_or_init_cli_toml(&settings)?;
+            let counts = sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+            let pruned = if args.prune {
+                prune_stale_server_configs(&mut cli_toml, &profiles)
             } else {
-                anyhow::bail!("Profile '{}' not found.", args.profile_name);
+                Vec::new()
+            };
+            if counts.added == 0 && counts.updated == 0 && pruned.is_empty() {
+                info!(
+                    "server_configs in {} already match {} ({} profile(s)). Nothing to do.",
+                    settings.cli_config_filename,
+                    settings.profiles_filename,
+                    profiles.0.len()
+                );
+            } else {
+                write_cli_toml(&settings, &cli_toml)?;
+                info!(
+                    "Synced server_configs: {} added, {} updated.",
+                    counts.added, counts.updated
+                );
+                if !pruned.is_empty() {
+                    info!(
+                        "Pruned {} stale server_configs entr{}: {}",
+                        pruned.len(),
+                        if pruned.len() == 1 { "y" } else { "ies" },
+                        pruned.join(", ")
+                    );
+                }
             }
         }
         Commands::Setup => {
@@ -1090,47 +6644,111 @@ fn main() -> Result<()> {
                 AppSettings::default()
             });
 
-            println!("Current configuration (leave blank to keep current value):");
-
-            let mut input = String::new();
-            println!(
-                "Profiles filename [{}]: ",         // Renamed
-                current_settings.profiles_filename  // Renamed
-            );
-            std::io::stdin().read_line(&mut input)?;
-            if !input.trim().is_empty() {
-                current_settings.profiles_filename = input.trim().to_string(); // Renamed
+            if !std::io::stdin().is_terminal() {
+                anyhow::bail!(
+                    "'setup' is interactive. Edit {} directly in scripts.",
+                    DEFAULT_CONFIG_FILENAME
+                );
             }
-            input.clear();
+            println!("Current configuration (press Enter to keep the current value):");
 
-            println!(
-                "SpacetimeDB CLI config directory (from home) [{}]: ",
-                current_settings.cli_config_dir_from_home
-            );
-            std::io::stdin().read_line(&mut input)?;
-            if !input.trim().is_empty() {
-                current_settings.cli_config_dir_from_home = input.trim().to_string();
-            }
-            input.clear();
+            let theme = prompt_theme();
+            let text = |prompt: &str, current: &str| -> Result<String> {
+                let value: String = Input::with_theme(theme.as_ref())
+                    .with_prompt(prompt)
+                    .default(current.to_string())
+                    .interact_text()?;
+                Ok(value.trim().to_string())
+            };
+            // Enter keeps the current value; '-' (or an empty value when unset) clears it.
+            let optional = |prompt: &str, current: Option<&str>| -> Result<Option<String>> {
+                let value: String = Input::with_theme(theme.as_ref())
+                    .with_prompt(format!("{} ('-' to clear)", prompt))
+                    .default(current.unwrap_or_default().to_string())
+                    .show_default(current.is_some())
+                    .allow_empty(true)
+                    .interact_text()?;
+                Ok(match value.trim() {
+                    "" | "-" => None,
+                    value => Some(value.to_string()),
+                })
+            };
 
-            println!(
-                "SpacetimeDB CLI config filename [{}]: ",
-                current_settings.cli_config_filename
-            );
-            std::io::stdin().read_line(&mut input)?;
-            if !input.trim().is_empty() {
-                current_settings.cli_config_filename = input.trim().to_string();
-            }
-            input.clear();
+            current_settings.profiles_filename =
+                text("Profiles filename", &current_settings.profiles_filename)?;
+            current_settings.cli_config_dir_from_home = text(
+                "SpacetimeDB CLI config directory (from home)",
+                &current_settings.cli_config_dir_from_home,
+            )?;
+            current_settings.cli_config_filename = text(
+                "SpacetimeDB CLI config filename",
+                &current_settings.cli_config_filename,
+            )?;
+            current_settings.cli_token_key =
+                text("SpacetimeDB CLI token key", &current_settings.cli_token_key)?;
 
-            println!(
-                "SpacetimeDB CLI token key [{}]: ",
-                current_settings.cli_token_key
-            );
-            std::io::stdin().read_line(&mut input)?;
-            if !input.trim().is_empty() {
-                current_settings.cli_token_key = input.trim().to_string();
+            let cli_config_paths = current_settings.cli_config_paths.join(", ");
+            current_settings.cli_config_paths = optional(
+                "SpacetimeDB CLI config paths, comma-separated, first is primary",
+                (!cli_config_paths.is_empty()).then_some(cli_config_paths.as_str()),
+            )?
+            .map(|paths| {
+                paths
+                    .split(',')
+                    .map(|path| path.trim().to_string())
+                    .filter(|path| !path.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+            current_settings.http_timeout_secs = Input::with_theme(theme.as_ref())
+                .with_prompt("HTTP timeout in seconds")
+                .default(current_settings.http_timeout_secs)
+                .interact_text()?;
+            current_settings.mask_reveal = Input::with_theme(theme.as_ref())
+                .with_prompt("Characters shown at each end of a masked token")
+                .default(current_settings.mask_reveal)
+                .interact_text()?;
+            current_settings.proxy = optional("HTTP proxy URL", current_settings.proxy.as_deref())?;
+            current_settings.default_profile = optional(
+                "Default profile for 'switch'",
+                current_settings.default_profile.as_deref(),
+            )?;
+            current_settings.admin_profile =
+                text("Profile for 'admin'", &current_settings.admin_profile)?;
+
+            let backends = [TokenBackend::File, TokenBackend::Keyring];
+            let backend_index = Select::with_theme(theme.as_ref())
+                .with_prompt("Token storage")
+                .items(&["file (profiles.toml)", "keyring (OS keyring)"])
+                .default(
+                    backends
+                        .iter()
+                        .position(|backend| *backend == current_settings.token_backend)
+                        .unwrap_or(0),
+                )
+                .interact()?;
+            current_settings.token_backend = backends[backend_index];
+            if current_settings.token_backend == TokenBackend::File {
+                current_settings.encrypt_tokens = Confirm::with_theme(theme.as_ref())
+                    .with_prompt("Encrypt tokens in profiles.toml with a passphrase?")
+                    .default(current_settings.encrypt_tokens)
+                    .interact()?;
             }
+            current_settings.audit_log = Confirm::with_theme(theme.as_ref())
+                .with_prompt(format!(
+                    "Record profile activations in {}?",
+                    AUDIT_LOG_FILENAME
+                ))
+                .default(current_settings.audit_log)
+                .interact()?;
+            current_settings.local_profiles = Confirm::with_theme(theme.as_ref())
+                .with_prompt(format!(
+                    "Prefer a project-local {}/{} when one is found?",
+                    LOCAL_PROFILES_DIR, current_settings.profiles_filename
+                ))
+                .default(current_settings.local_profiles)
+                .interact()?;
 
             write_app_settings(&current_settings)?;
         }
@@ -1138,3 +6756,703 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(protocol: &str, host: &str) -> (String, String) {
+        (protocol.to_string(), host.to_string())
+    }
+
+    #[test]
+    fn normalize_server_target_maps_local_shortcut() {
+        assert_eq!(normalize_server_target("local"), target("http", "127.0.0.1:3000"));
+    }
+
+    #[test]
+    fn local_shortcut_accepts_a_port() {
+        assert_eq!(local_port("local"), Some(3000));
+        assert_eq!(local_port("local:6000"), Some(6000));
+        assert_eq!(local_port("local:abc"), None);
+        assert_eq!(local_port("local:0"), None);
+        assert_eq!(local_port("localhost:6000"), None);
+
+        assert_eq!(
+            normalize_server_target("local:6000"),
+            target("http", "127.0.0.1:6000")
+        );
+        assert_eq!(
+            normalize_identity_base("local:6000"),
+            "http://127.0.0.1:6000"
+        );
+        assert_eq!(normalize_server_path("local:6000"), None);
+
+        assert!(validate_address("local").is_ok());
+        assert!(validate_address("local:6000").is_ok());
+        assert!(validate_address("local:abc").is_err());
+        assert!(validate_address("local:70000").is_err());
+    }
+
+    #[test]
+    fn normalize_identity_base_uses_http_schemes() {
+        assert_eq!(
+            normalize_identity_base("https://example.com/spacetime"),
+            "https://example.com"
+        );
+        assert_eq!(
+            normalize_identity_base("wss://example.com/spacetime"),
+            "https://example.com"
+        );
+        assert_eq!(
+            normalize_identity_base("ws://gateway.example.com:8080/api/spacetime"),
+            "http://gateway.example.com:8080/api"
+        );
+        assert_eq!(
+            normalize_identity_base("db.example.com:3000"),
+            "http://db.example.com:3000"
+        );
+        assert_eq!(
+            normalize_identity_base("https://example.com:443/"),
+            "https://example.com:443"
+        );
+    }
+
+    #[test]
+    fn server_issued_login_excludes_maincloud() {
+        assert!(supports_server_issued_login("local"));
+        assert!(supports_server_issued_login("local:6000"));
+        assert!(supports_server_issued_login(
+            "https://staging.example.com/spacetime"
+        ));
+        assert!(supports_server_issued_login("db.example.com:3000"));
+        assert!(!supports_server_issued_login("maincloud"));
+        assert!(!supports_server_issued_login(
+            "https://maincloud.spacetimedb.com"
+        ));
+    }
+
+    #[test]
+    fn normalize_server_target_handles_ws_schemes() {
+        assert_eq!(
+            normalize_server_target("ws://example.com/spacetime"),
+            target("http", "example.com")
+        );
+        assert_eq!(
+            normalize_server_target("wss://example.com/spacetime"),
+            target("https", "example.com")
+        );
+    }
+
+    #[test]
+    fn normalize_server_target_strips_trailing_slashes() {
+        assert_eq!(
+            normalize_server_target("wss://example.com/spacetime/"),
+            target("https", "example.com")
+        );
+        assert_eq!(
+            normalize_server_target("https://example.com//"),
+            target("https", "example.com")
+        );
+        assert_eq!(
+            normalize_server_target("ws://example.com/"),
+            target("http", "example.com")
+        );
+    }
+
+    #[test]
+    fn normalize_server_target_preserves_ipv6_hosts() {
+        assert_eq!(
+            normalize_server_target("http://[::1]:3000/spacetime"),
+            target("http", "[::1]:3000")
+        );
+        assert_eq!(
+            normalize_server_target("[::1]:3000"),
+            target("http", "[::1]:3000")
+        );
+    }
+
+    #[test]
+    fn normalize_server_target_preserves_explicit_ports() {
+        assert_eq!(
+            normalize_server_target("127.0.0.1:3000"),
+            target("http", "127.0.0.1:3000")
+        );
+        assert_eq!(
+            normalize_server_target("example.com:443"),
+            target("http", "example.com:443")
+        );
+        assert_eq!(
+            normalize_server_target("https://example.com:8443/spacetime"),
+            target("https", "example.com:8443")
+        );
+    }
+
+    #[test]
+    fn normalize_server_target_keeps_written_default_ports() {
+        assert_eq!(
+            normalize_server_target("https://example.com:443"),
+            target("https", "example.com:443")
+        );
+        assert_eq!(
+            normalize_server_target("http://user@example.com:80/spacetime?x=1"),
+            target("http", "example.com:80")
+        );
+        assert_eq!(
+            normalize_server_target("wss://[::1]:443"),
+            target("https", "[::1]:443")
+        );
+        assert_eq!(
+            normalize_server_target("https://example.com"),
+            target("https", "example.com")
+        );
+    }
+
+    #[test]
+    fn validate_address_accepts_supported_forms() {
+        for address in [
+            "local",
+            "http://127.0.0.1:3000",
+            "https://maincloud.spacetimedb.com",
+            "wss://gateway.example.com/api/spacetime",
+            "localhost:3000",
+        ] {
+            assert!(validate_address(address).is_ok(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn validate_address_rejects_malformed_input() {
+        for address in [
+            "",
+            "htttp://host",
+            "https://host ",
+            "https://",
+            "http://host:notaport",
+        ] {
+            assert!(validate_address(address).is_err(), "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn dry_run_previews_mask_token_values() {
+        let text = "[dev]\ntoken = \"eyJhbGciOiJIUzI1NiJ9.abc.signature\"\naddress = \"local\"\n\
+                    [ci]\ntoken = \"${ENV:CI_TOKEN}\"\n";
+        let masked = mask_toml_tokens(text, 5);
+        assert!(masked.contains("token = \"eyJhb...ature\"\n"));
+        assert!(masked.contains("address = \"local\"\n"));
+        assert!(masked.contains("token = \"${ENV:CI_TOKEN}\"\n"));
+        assert_eq!(
+            mask_toml_tokens("spacetimedb_token = \"abcdefghijklmnop\" # note\n", 3),
+            "spacetimedb_token = \"abc...nop\" # note\n"
+        );
+    }
+
+    #[test]
+    fn backup_file_names_differ_per_attempt() {
+        let timestamp = "2024-05-01T12-30-00.123456Z";
+        assert_eq!(
+            backup_file_name("profiles", timestamp, 0),
+            "profiles.2024-05-01T12-30-00.123456Z.bak"
+        );
+        assert_eq!(
+            backup_file_name("profiles", timestamp, 2),
+            "profiles.2024-05-01T12-30-00.123456Z.2.bak"
+        );
+    }
+
+    #[test]
+    fn looks_like_token_flags_paste_errors() {
+        let jwt = "eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiJ4In0.sig";
+        assert!(looks_like_token(jwt));
+        assert!(!looks_like_token(""));
+        assert!(!looks_like_token(&format!("{} ", jwt)));
+        assert!(!looks_like_token(
+            "eyJhbGciOiJIUzI1NiJ9.eyJzdWIi OiJ4In0.sig"
+        ));
+        assert!(!looks_like_token("eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiJ4In0"));
+    }
+
+    #[test]
+    fn normalize_server_path_keeps_sub_paths() {
+        assert_eq!(
+            normalize_server_path("https://gateway.example.com/api/spacetime"),
+            Some("/api".to_string())
+        );
+        assert_eq!(
+            normalize_server_path("https://gateway.example.com/team/api/"),
+            Some("/team/api".to_string())
+        );
+        assert_eq!(
+            normalize_server_path("gateway.example.com:8080/api/spacetime"),
+            Some("/api".to_string())
+        );
+        assert_eq!(
+            normalize_server_target("https://gateway.example.com/api/spacetime"),
+            target("https", "gateway.example.com")
+        );
+    }
+
+    #[test]
+    fn normalize_server_path_ignores_root_deployments() {
+        assert_eq!(normalize_server_path("local"), None);
+        assert_eq!(normalize_server_path("https://example.com/spacetime"), None);
+        assert_eq!(normalize_server_path("https://example.com//"), None);
+        assert_eq!(normalize_server_path("127.0.0.1:3000"), None);
+    }
+
+    #[test]
+    fn update_cli_server_target_writes_and_clears_path() {
+        let mut cli_toml = DocumentMut::new();
+        update_cli_server_target(
+            &mut cli_toml,
+            "gw",
+            "https://gateway.example.com/api/spacetime",
+        );
+        let entry = &cli_toml["server_configs"][0];
+        assert_eq!(entry["host"].as_str(), Some("gateway.example.com"));
+        assert_eq!(entry["path"].as_str(), Some("/api"));
+
+        update_cli_server_target(&mut cli_toml, "gw", "https://example.com/spacetime");
+        let entry = &cli_toml["server_configs"][0];
+        assert_eq!(entry["host"].as_str(), Some("example.com"));
+        assert!(entry.get("path").is_none());
+    }
+
+    #[test]
+    fn switching_profiles_round_trips_a_realistic_cli_toml() {
+        let original = r#"# Written by the SpacetimeDB CLI
+default_server = "local"
+spacetimedb_token = "old-token" # replaced on switch
+
+[[server_configs]]
+nickname = "local"
+host = "127.0.0.1:3000"
+protocol = "http"
+
+[[server_configs]]
+nickname = "maincloud"
+host = "maincloud.spacetimedb.com"
+protocol = "https"
+
+# Kept by hand
+[[server_configs]]
+nickname = "dev"
+host = "dev.example.com"
+protocol = "https"
+ecdsa_public_key = "-----BEGIN PUBLIC KEY-----"
+
+[telemetry]
+enabled = false # opted out
+"#;
+        let mut profiles = UserProfiles::default();
+        profiles.0.insert(
+            "dev".to_string(),
+            Profile::new(
+                "dev-token".to_string(),
+                "https://dev.example.com".to_string(),
+            ),
+        );
+        profiles.0.insert(
+            "staging".to_string(),
+            Profile::new(
+                "staging-token".to_string(),
+                "https://staging.example.com".to_string(),
+            ),
+        );
+        let mut cli_toml: DocumentMut = original.parse().unwrap();
+        set_toml_value(&mut cli_toml, "spacetimedb_token", "staging-token");
+        sync_server_configs_from_profiles(&mut cli_toml, &profiles);
+        update_cli_server_target(&mut cli_toml, "staging", "https://staging.example.com");
+        let expected = original
+            .replace(
+                "default_server = \"local\"",
+                "default_server = \"staging\"",
+            )
+            .replace("\"old-token\"", "\"staging-token\"")
+            .replace(
+                "\n[telemetry]",
+                "\n[[server_configs]]\nnickname = \"staging\"\nhost = \"staging.example.com\"\nprotocol = \"https\"\n\n[telemetry]",
+            );
+        assert_eq!(cli_toml.to_string(), expected);
+    }
+
+    #[test]
+    fn inline_server_configs_are_converted_keeping_their_keys() {
+        let mut cli_toml: DocumentMut = r#"
+default_server = "local"
+server_configs = [
+    { nickname = "local", host = "127.0.0.1:3000", protocol = "http", ecdsa_public_key = "key" },
+]
+spacetimedb_token = "token"
+"#
+        .parse()
+        .unwrap();
+        update_cli_server_target(&mut cli_toml, "dev", "https://dev.example.com");
+
+        let entries = cli_toml["server_configs"].as_array_of_tables().unwrap();
+        assert_eq!(entries.len(), 2);
+        let local = entries.get(0).unwrap();
+        assert_eq!(local["nickname"].as_str(), Some("local"));
+        assert_eq!(local["ecdsa_public_key"].as_str(), Some("key"));
+        assert_eq!(
+            entries.get(1).unwrap()["host"].as_str(),
+            Some("dev.example.com")
+        );
+        assert_eq!(cli_toml["spacetimedb_token"].as_str(), Some("token"));
+        assert_eq!(cli_toml["default_server"].as_str(), Some("dev"));
+
+        let mut unexpected: DocumentMut = "server_configs = \"local\"\n".parse().unwrap();
+        update_cli_server_target(&mut unexpected, "dev", "local");
+        assert_eq!(unexpected["server_configs"].as_str(), Some("local"));
+    }
+
+    #[test]
+    fn prune_stale_server_configs_keeps_builtin_and_default_entries() {
+        let mut cli_toml: DocumentMut = r#"
+default_server = "old-default"
+
+[[server_configs]]
+nickname = "dev"
+host = "127.0.0.1:3000"
+
+[[server_configs]]
+nickname = "deleted"
+host = "example.com"
+
+[[server_configs]]
+nickname = "maincloud"
+host = "maincloud.spacetimedb.com"
+
+[[server_configs]]
+nickname = "old-default"
+host = "example.org"
+"#
+        .parse()
+        .unwrap();
+        let profiles = UserProfiles(HashMap::from([(
+            "dev".to_string(),
+            Profile::new("token".to_string(), "local".to_string()),
+        )]));
+
+        let removed = prune_stale_server_configs(&mut cli_toml, &profiles);
+        assert_eq!(removed, vec!["deleted".to_string()]);
+        let nicknames: Vec<&str> = cli_toml["server_configs"]
+            .as_array_of_tables()
+            .unwrap()
+            .iter()
+            .filter_map(|table| table.get("nickname").and_then(|v| v.as_str()))
+            .collect();
+        assert_eq!(nicknames, vec!["dev", "maincloud", "old-default"]);
+    }
+
+    #[test]
+    fn apply_address_protocol_prefixes_bare_hosts_only() {
+        assert_eq!(
+            apply_address_protocol("db.example.com", Some(AddressProtocol::Https)),
+            "https://db.example.com"
+        );
+        assert_eq!(
+            apply_address_protocol("db.example.com:3000", Some(AddressProtocol::Http)),
+            "http://db.example.com:3000"
+        );
+        assert_eq!(
+            apply_address_protocol("http://db.example.com", Some(AddressProtocol::Https)),
+            "http://db.example.com"
+        );
+        assert_eq!(
+            apply_address_protocol("local", Some(AddressProtocol::Https)),
+            "local"
+        );
+    }
+
+    #[test]
+    fn parse_env_var_splits_on_first_equals() {
+        assert_eq!(
+            parse_env_var("SPACETIME_LOG=debug"),
+            Ok(("SPACETIME_LOG".to_string(), "debug".to_string()))
+        );
+        assert_eq!(
+            parse_env_var("OPTS=a=b"),
+            Ok(("OPTS".to_string(), "a=b".to_string()))
+        );
+        assert_eq!(
+            parse_env_var("EMPTY="),
+            Ok(("EMPTY".to_string(), String::new()))
+        );
+        assert!(parse_env_var("NOVALUE").is_err());
+        assert!(parse_env_var("=value").is_err());
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("abc.def"), "'abc.def'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME `x` \"y\""), "'$HOME `x` \"y\"'");
+    }
+
+    #[test]
+    fn cli_toml_changes_lists_differing_fields_with_masked_tokens() {
+        let current: DocumentMut = r#"
+spacetimedb_token = "aaaaaaaaaaaaaaaaaaaa"
+default_host = "local"
+default_server = "dev"
+
+[[server_configs]]
+nickname = "dev"
+host = "127.0.0.1:3000"
+protocol = "http"
+"#
+        .parse()
+        .unwrap();
+        let proposed: DocumentMut = r#"
+spacetimedb_token = "bbbbbbbbbbbbbbbbbbbb"
+default_host = "https://prod.example.com"
+default_server = "prod"
+
+[[server_configs]]
+nickname = "dev"
+host = "127.0.0.1:3000"
+protocol = "http"
+
+[[server_configs]]
+nickname = "prod"
+host = "prod.example.com"
+protocol = "https"
+"#
+        .parse()
+        .unwrap();
+
+        let changes = cli_toml_changes(&current, &proposed, "spacetimedb_token", 5);
+        let fields: Vec<&str> = changes.iter().map(|c| c.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "spacetimedb_token",
+                "default_host",
+                "default_server",
+                "server_configs[prod].host",
+                "server_configs[prod].protocol",
+            ]
+        );
+        assert_eq!(changes[0].current.as_deref(), Some("aaaaa...aaaaa"));
+        assert_eq!(changes[3].current, None);
+        assert!(cli_toml_changes(&current, &current, "spacetimedb_token", 5).is_empty());
+    }
+
+    #[test]
+    fn address_aliases_expand_only_exact_names() {
+        let mut settings = AppSettings::default();
+        settings.address_aliases.insert(
+            "staging".to_string(),
+            "https://staging.example.com".to_string(),
+        );
+        assert_eq!(
+            resolve_address_alias(&settings, "staging"),
+            "https://staging.example.com"
+        );
+        assert_eq!(
+            resolve_address_alias(&settings, "staging.example.com"),
+            "staging.example.com"
+        );
+        assert!(validate_alias_name("prod_eu-1").is_ok());
+        assert!(validate_alias_name("local").is_err());
+        assert!(validate_alias_name("db.example.com").is_err());
+        assert!(validate_alias_name("").is_err());
+    }
+
+    #[test]
+    fn normalized_address_fixes_cosmetic_issues_and_flags_scheme_changes() {
+        assert_eq!(normalized_address("https://example.com/spacetime"), None);
+        assert_eq!(normalized_address("local"), None);
+        assert_eq!(normalized_address("db.example.com:3000"), None);
+        assert_eq!(
+            normalized_address(" HTTPS://Example.com/spacetime/ "),
+            Some(("https://example.com/spacetime".to_string(), false))
+        );
+        assert_eq!(
+            normalized_address("local "),
+            Some(("local".to_string(), false))
+        );
+        assert_eq!(normalized_address("wss://example.com"), None);
+        assert_eq!(
+            normalized_address("WSS://Example.com/"),
+            Some(("wss://example.com".to_string(), false))
+        );
+        assert_eq!(
+            normalized_address("htttp://example.com"),
+            Some(("http://example.com".to_string(), true))
+        );
+    }
+
+    #[test]
+    fn normalized_address_flags_port_and_query_changes() {
+        assert_eq!(normalized_address("https://example.com:443/x"), None);
+        assert_eq!(
+            normalized_address("HTTPS://Example.com:443/x/"),
+            Some(("https://example.com:443/x".to_string(), false))
+        );
+        assert_eq!(
+            normalized_address("https://Example.com/x?a=1"),
+            Some(("https://example.com/x".to_string(), true))
+        );
+        assert_eq!(
+            normalized_address("https://example.com/x#top"),
+            Some(("https://example.com/x".to_string(), true))
+        );
+    }
+
+    #[test]
+    fn error_json_lists_the_context_chain() {
+        let error = anyhow::anyhow!("connection refused")
+            .context("Failed to call http://127.0.0.1:3000/v1/ping")
+            .context("Ping failed");
+        let value = error_json(&error, ErrorCategory::General);
+        assert_eq!(value["error"]["message"], "Ping failed");
+        assert_eq!(
+            value["error"]["context"],
+            serde_json::json!([
+                "Failed to call http://127.0.0.1:3000/v1/ping",
+                "connection refused"
+            ])
+        );
+        assert_eq!(value["error"]["category"], ErrorCategory::General.as_str());
+    }
+
+    #[test]
+    fn env_token_reference_matches_only_the_full_pattern() {
+        assert_eq!(env_token_reference("${ENV:CI_TOKEN}"), Some("CI_TOKEN"));
+        assert_eq!(env_token_reference("${ENV:}"), None);
+        assert_eq!(env_token_reference("${ENV:CI_TOKEN"), None);
+        assert_eq!(env_token_reference("eyJhbGciOi.abc.def"), None);
+    }
+
+    #[test]
+    fn mask_token_hides_short_tokens_and_reveals_ends() {
+        assert_eq!(mask_token("", 5), "***");
+        assert_eq!(mask_token("abc", 5), "***");
+        assert_eq!(mask_token("0123456789", 5), "***");
+        assert_eq!(mask_token("0123456789a", 5), "01234...6789a");
+        assert_eq!(mask_token("abcdefg", 3), "abc...efg");
+        assert_eq!(mask_token("abcdef", 3), "***");
+        assert_eq!(mask_token("äöüßéèà", 3), "äöü...éèà");
+    }
+
+    #[test]
+    fn list_summary_reflects_filters_and_environment() {
+        let profiles: HashMap<String, Profile> = [
+            ("a", "local"),
+            ("b", "local"),
+            ("c", "https://maincloud.spacetimedb.com"),
+        ]
+        .into_iter()
+        .map(|(name, address)| {
+            (
+                name.to_string(),
+                Profile::new("token".to_string(), address.to_string()),
+            )
+        })
+        .collect();
+        let args = |argv: &[&str]| ListArgs::try_parse_from(argv).unwrap();
+
+        assert_eq!(
+            list_summary(&profiles, &args(&["list"]), Some("local")),
+            "3 profiles (2 in environment 'local')"
+        );
+        assert_eq!(
+            list_summary(&profiles, &args(&["list"]), None),
+            "3 profiles"
+        );
+        assert_eq!(
+            list_summary(&HashMap::new(), &args(&["list", "--env"]), Some("local")),
+            "0 profiles in environment 'local'"
+        );
+        assert_eq!(
+            list_summary(
+                &profiles,
+                &args(&["list", "--tag", "ci", "--stale"]),
+                Some("maincloud")
+            ),
+            "3 profiles tagged 'ci' with expired tokens (0 in environment 'maincloud')"
+        );
+    }
+
+    #[test]
+    fn active_session_address_prefers_matching_server_config() {
+        let doc = |text: &str| text.parse::<DocumentMut>().unwrap();
+
+        let tls = doc(
+            "default_host = \"staging.example.com\"\ndefault_server = \"staging\"\n\n[[server_configs]]\nnickname = \"staging\"\nhost = \"staging.example.com\"\nprotocol = \"https\"\npath = \"/api\"\n",
+        );
+        assert_eq!(
+            active_session_address(&tls).as_deref(),
+            Some("https://staging.example.com/api")
+        );
+
+        let local = doc(
+            "default_host = \"local\"\ndefault_server = \"dev\"\n\n[[server_configs]]\nnickname = \"dev\"\nhost = \"127.0.0.1:3000\"\nprotocol = \"http\"\n",
+        );
+        assert_eq!(active_session_address(&local).as_deref(), Some("local"));
+
+        let host_only = doc("default_host = \"example.com:3000\"\n");
+        assert_eq!(
+            active_session_address(&host_only).as_deref(),
+            Some("example.com:3000")
+        );
+        assert_eq!(active_session_address(&doc("")), None);
+    }
+
+    #[test]
+    fn forget_environment_keeps_builtin_and_profile_entries() {
+        let mut cli_toml = "default_host = \"https://old.example.com\"\ndefault_server = \"old\"\n\n[[server_configs]]\nnickname = \"old\"\nhost = \"old.example.com\"\nprotocol = \"https\"\n\n[[server_configs]]\nnickname = \"kept\"\nhost = \"old.example.com\"\nprotocol = \"https\"\n\n[[server_configs]]\nnickname = \"other\"\nhost = \"other.example.com\"\nprotocol = \"https\"\n"
+            .parse::<DocumentMut>()
+            .unwrap();
+        let mut profiles = UserProfiles::default();
+        profiles.0.insert(
+            "kept".to_string(),
+            Profile::new("token".to_string(), "old.example.com:443".to_string()),
+        );
+
+        let (removed, cleared) =
+            forget_environment(&mut cli_toml, &profiles, "https://old.example.com");
+        assert_eq!(removed, vec!["old".to_string()]);
+        assert!(cleared);
+        assert!(cli_toml.get("default_host").is_none());
+        assert!(cli_toml.get("default_server").is_none());
+        let nicknames: Vec<&str> = cli_toml["server_configs"]
+            .as_array_of_tables()
+            .unwrap()
+            .iter()
+            .filter_map(|table| table.get("nickname").and_then(|v| v.as_str()))
+            .collect();
+        assert_eq!(nicknames, vec!["kept", "other"]);
+
+        let (removed, cleared) = forget_environment(&mut cli_toml, &profiles, "local");
+        assert!(removed.is_empty());
+        assert!(!cleared);
+    }
+
+    #[test]
+    fn render_table_pads_columns_to_widest_cell() {
+        let rows = vec![
+            vec!["dev".to_string(), "local".to_string(), "*".to_string()],
+            vec![
+                "production".to_string(),
+                "https://prod.example.com".to_string(),
+                String::new(),
+            ],
+        ];
+        assert_eq!(
+            render_table(&["NAME", "ADDRESS", "CURRENT"], &rows),
+            "NAME        ADDRESS                   CURRENT\n\
+             dev         local                     *\n\
+             production  https://prod.example.com"
+        );
+        assert_eq!(render_table(&["NAME"], &[]), "NAME");
+    }
+
+    #[test]
+    fn cli_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+}